@@ -1,14 +1,35 @@
 in vec3 v_normal;
+in vec2 v_uv;
+in vec4 v_material;
+in vec4 v_tangent;
 in float v_flogz;
 
 out vec4 out_color;
 
 const float Fcoef_half = 1.0 / log2(1e20 + 1.0);
 
+// Rock, sand, snow and ice, in the order of `Material`
+const vec3 material_colors[4] = vec3[4](
+    vec3(0.45, 0.40, 0.36),
+    vec3(0.86, 0.76, 0.52),
+    vec3(0.95, 0.96, 0.98),
+    vec3(0.62, 0.80, 0.92)
+);
+
 void main() {
     vec3 normal = normalize(v_normal);
+
+    // Bumps in texture space, bent onto the surface by the tangent frame
+    vec3 tangent = normalize(v_tangent.xyz);
+    vec3 bitangent = cross(normal, tangent) * v_tangent.w;
+    vec2 wave = 6.2831853 * v_uv;
+    vec2 slope = 0.15 * vec2(cos(wave.x) * sin(wave.y), sin(wave.x) * cos(wave.y));
+    normal = normalize(normal - slope.x * tangent - slope.y * bitangent);
+
     float light = max(0.1, dot(normal, normalize(vec3(1.0, 1.0, 1.0))));
-    out_color = vec4(light * (normal * 0.5 + 0.5), 1.0);
+    vec3 color = material_colors[0] * v_material.x + material_colors[1] * v_material.y +
+                 material_colors[2] * v_material.z + material_colors[3] * v_material.w;
+    out_color = vec4(light * color, 1.0);
 
     // Perspective-correct depth interpolation
     // For logarithmic depth buffer
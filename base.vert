@@ -1,10 +1,16 @@
 layout(location = ATTRIB_POSITION) in vec3 position;
 layout(location = ATTRIB_NORMAL) in vec3 normal;
+layout(location = ATTRIB_UV) in vec2 uv;
+layout(location = ATTRIB_MATERIAL) in vec4 material;
+layout(location = ATTRIB_TANGENT) in vec4 tangent;
 
 layout(location = UNIFORM_PROJECTION) uniform mat4x4 projection;
 layout(location = UNIFORM_MODEL_VIEW) uniform mat4x4 model_view;
 
 out vec3 v_normal;
+out vec2 v_uv;
+out vec4 v_material;
+out vec4 v_tangent;
 out float v_flogz;
 
 const float Fcoef = 2.0 / log2(1e20 + 1.0);
@@ -20,5 +26,8 @@ void main() {
 
     gl_Position.z = (log2(max(1e-6, 1.0 + gl_Position.w)) * Fcoef - 1.0) * gl_Position.w;
     v_normal = normal;
+    v_uv = uv;
+    v_material = material;
+    v_tangent = tangent;
     v_flogz = 1.0 + gl_Position.w;
 }
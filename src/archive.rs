@@ -0,0 +1,232 @@
+#![allow(dead_code)]
+
+use std::io::prelude::*;
+use std::io::ErrorKind;
+
+use crate::error::Error;
+use crate::geometry::Vertex;
+use crate::isosurface;
+use crate::octree::MAX_LEVEL;
+
+const MAGIC: &[u8; 4] = b"UVBK";
+const VERSION: u32 = 3;
+
+/// A single baked octree node: where it sits in the tree and its mesh, in
+/// node-local coordinates (the same space `Geometry` is drawn in).
+pub struct Chunk {
+    pub path: Vec<i8>,
+    pub level: i32,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub data: Vec<Vertex>,
+}
+
+/// Streams chunks to a baked planet archive.
+///
+/// Layout (little endian): magic, version, then chunks until end of file.
+pub struct ArchiveWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut inner: W) -> Result<ArchiveWriter<W>, Error> {
+        inner.write_all(MAGIC)?;
+        write_u32(&mut inner, VERSION)?;
+        Ok(ArchiveWriter { inner })
+    }
+
+    pub fn write(&mut self, chunk: &Chunk) -> Result<(), Error> {
+        let w = &mut self.inner;
+        write_u32(w, chunk.level as u32)?;
+        write_f64(w, chunk.x)?;
+        write_f64(w, chunk.y)?;
+        write_f64(w, chunk.z)?;
+        write_u32(w, chunk.path.len() as u32)?;
+        for index in &chunk.path {
+            w.write_all(&[*index as u8])?;
+        }
+        write_u32(w, chunk.data.len() as u32)?;
+        for vertex in &chunk.data {
+            write_vertex(w, vertex)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads chunks back from an archive written by `ArchiveWriter`.
+pub struct ArchiveReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(mut inner: R) -> Result<ArchiveReader<R>, Error> {
+        let mut magic = [0u8; 4];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::Archive(String::from("not a baked planet archive")));
+        }
+        let version = read_u32(&mut inner)?;
+        if version != VERSION {
+            return Err(Error::Archive(format!("unsupported archive version {}", version)));
+        }
+        Ok(ArchiveReader { inner })
+    }
+
+    /// Returns the next chunk, or `None` at the end of the archive.
+    pub fn read(&mut self) -> Result<Option<Chunk>, Error> {
+        let r = &mut self.inner;
+        let level = match read_u32(r) {
+            Ok(level) => level as i32,
+            Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(Error::from(err)),
+        };
+        let x = read_f64(r)?;
+        let y = read_f64(r)?;
+        let z = read_f64(r)?;
+        // Checked before allocating, so a corrupt length is an error rather
+        // than an aborted allocation
+        let path_len = read_u32(r)? as usize;
+        if path_len > MAX_LEVEL as usize {
+            return Err(Error::Archive(format!("chunk path of {} steps is too long", path_len)));
+        }
+        let mut path = vec![0u8; path_len];
+        r.read_exact(&mut path)?;
+        let num_vertices = read_u32(r)? as usize;
+        if num_vertices > isosurface::MAX_VERTICES {
+            return Err(Error::Archive(format!("chunk of {} vertices is too long", num_vertices)));
+        }
+        let mut data = Vec::<Vertex>::with_capacity(num_vertices);
+        for _ in 0..num_vertices {
+            data.push(read_vertex(r)?);
+        }
+        Ok(Some(Chunk {
+            path: path.into_iter().map(|index| index as i8).collect(),
+            level,
+            x,
+            y,
+            z,
+            data,
+        }))
+    }
+}
+
+pub(crate) fn write_u32(w: &mut impl Write, value: u32) -> std::io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_f32(w: &mut impl Write, value: f32) -> std::io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_f64(w: &mut impl Write, value: f64) -> std::io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_i64(w: &mut impl Write, value: i64) -> std::io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn read_u32(r: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_f32(r: &mut impl Read) -> std::io::Result<f32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_f64(r: &mut impl Read) -> std::io::Result<f64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_i64(r: &mut impl Read) -> std::io::Result<i64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(i64::from_le_bytes(bytes))
+}
+
+pub(crate) fn write_vertex(w: &mut impl Write, vertex: &Vertex) -> std::io::Result<()> {
+    for value in vertex.position.iter().chain(&vertex.normal).chain(&vertex.uv).chain(&vertex.material).chain(&vertex.tangent) {
+        write_f32(w, *value)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_vertex(r: &mut impl Read) -> std::io::Result<Vertex> {
+    Ok(Vertex {
+        position: [read_f32(r)?, read_f32(r)?, read_f32(r)?],
+        normal: [read_f32(r)?, read_f32(r)?, read_f32(r)?],
+        uv: [read_f32(r)?, read_f32(r)?],
+        material: [read_f32(r)?, read_f32(r)?, read_f32(r)?, read_f32(r)?],
+        tangent: [read_f32(r)?, read_f32(r)?, read_f32(r)?, read_f32(r)?],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() {
+        let chunk = Chunk {
+            path: vec![3, 7],
+            level: 2,
+            x: 0.125,
+            y: -0.375,
+            z: 0.125,
+            data: vec![Vertex { position: [1.0, 2.0, 3.0], normal: [0.0, 1.0, 0.0], uv: [0.5, 0.25], material: [0.0, 0.5, 0.5, 0.0], tangent: [1.0, 0.0, 0.0, -1.0] }],
+        };
+
+        let mut writer = ArchiveWriter::new(Vec::new()).unwrap();
+        writer.write(&chunk).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let mut reader = ArchiveReader::new(Cursor::new(bytes)).unwrap();
+        let read = reader.read().unwrap().expect("Missing chunk");
+        assert!(read.path == chunk.path);
+        assert!(read.level == 2);
+        assert!(read.y == -0.375);
+        assert!(read.data.len() == 1);
+        assert!(read.data[0].uv == [0.5, 0.25]);
+        assert!(read.data[0].material == [0.0, 0.5, 0.5, 0.0]);
+        assert!(read.data[0].tangent == [1.0, 0.0, 0.0, -1.0]);
+        assert!(reader.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_lengths() {
+        let chunk = Chunk { path: vec![1], level: 1, x: 0.0, y: 0.0, z: 0.0, data: Vec::new() };
+        let mut writer = ArchiveWriter::new(Vec::new()).unwrap();
+        writer.write(&chunk).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        // The path length follows the magic, version, level and centre
+        let at = 4 + 4 + 4 + 3 * 8;
+        let mut long_path = bytes.clone();
+        long_path[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(ArchiveReader::new(Cursor::new(long_path)).unwrap().read().is_err());
+
+        // The vertex count follows the path
+        let at = at + 4 + 1;
+        let mut many_vertices = bytes;
+        many_vertices[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(ArchiveReader::new(Cursor::new(many_vertices)).unwrap().read().is_err());
+    }
+
+    #[test]
+    fn bad_magic() {
+        assert!(ArchiveReader::new(Cursor::new(b"NOPE\x01\0\0\0".to_vec())).is_err());
+    }
+}
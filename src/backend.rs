@@ -0,0 +1,136 @@
+#![allow(dead_code)]
+
+use std::cell::{Cell, RefCell};
+use cgmath::prelude::*;
+use cgmath::Matrix4;
+use gl::types::*;
+use crate::geometry::{Geometry, Vertex};
+use crate::shader::Uniform;
+
+/// Where the octree sends chunk meshes once the worker has generated them.
+///
+/// Methods take `&self` so nodes can free meshes while the tree is being
+/// walked; implementations needing state use interior mutability.
+pub trait MeshBackend {
+    type Mesh;
+
+    fn upload(&self, data: &[Vertex]) -> Self::Mesh;
+    fn free(&self, mesh: Self::Mesh);
+    fn draw(&self, mesh: &Self::Mesh, model_view: Matrix4<GLfloat>);
+}
+
+/// Uploads meshes to OpenGL buffers and draws them with the current shader.
+pub struct GlBackend;
+
+impl MeshBackend for GlBackend {
+    type Mesh = Geometry;
+
+    #[inline]
+    fn upload(&self, data: &[Vertex]) -> Geometry {
+        Geometry::from(data)
+    }
+
+    #[inline]
+    fn free(&self, geometry: Geometry) {
+        drop(geometry);
+    }
+
+    fn draw(&self, geometry: &Geometry, model_view: Matrix4<GLfloat>) {
+        unsafe {
+            gl::UniformMatrix4fv(Uniform::ModelView as GLint,
+                                 1,
+                                 gl::FALSE,
+                                 model_view.as_ptr());
+        }
+        geometry.draw();
+    }
+}
+
+/// Throws meshes away, for tools that generate chunks without ever drawing
+/// them.
+pub struct NullBackend;
+
+impl MeshBackend for NullBackend {
+    type Mesh = ();
+
+    #[inline]
+    fn upload(&self, _data: &[Vertex]) {}
+
+    #[inline]
+    fn free(&self, _mesh: ()) {}
+
+    #[inline]
+    fn draw(&self, _mesh: &(), _model_view: Matrix4<GLfloat>) {}
+}
+
+/// Handle to a mesh "uploaded" to a `RecordingBackend`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordedMesh {
+    pub id: usize,
+    pub num_vertices: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Upload(RecordedMesh),
+    Free(RecordedMesh),
+    Draw(RecordedMesh, Matrix4<GLfloat>),
+}
+
+/// Keeps meshes in memory and records every call, for tests and tools that
+/// run without a GL context.
+#[derive(Default)]
+pub struct RecordingBackend {
+    next_id: Cell<usize>,
+    events: RefCell<Vec<Event>>,
+}
+
+impl RecordingBackend {
+    pub fn new() -> RecordingBackend {
+        Default::default()
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        self.events.borrow().clone()
+    }
+
+    pub fn clear(&self) {
+        self.events.borrow_mut().clear();
+    }
+
+    pub fn uploads(&self) -> usize {
+        self.events.borrow().iter().filter(|event| matches!(event, Event::Upload(_))).count()
+    }
+
+    pub fn frees(&self) -> usize {
+        self.events.borrow().iter().filter(|event| matches!(event, Event::Free(_))).count()
+    }
+
+    pub fn draws(&self) -> usize {
+        self.events.borrow().iter().filter(|event| matches!(event, Event::Draw(..))).count()
+    }
+
+    /// Number of meshes uploaded and not yet freed.
+    pub fn resident(&self) -> usize {
+        self.uploads() - self.frees()
+    }
+}
+
+impl MeshBackend for RecordingBackend {
+    type Mesh = RecordedMesh;
+
+    fn upload(&self, data: &[Vertex]) -> RecordedMesh {
+        let mesh = RecordedMesh { id: self.next_id.get(), num_vertices: data.len() };
+        self.next_id.set(mesh.id + 1);
+        self.events.borrow_mut().push(Event::Upload(mesh));
+        mesh
+    }
+
+    fn free(&self, mesh: RecordedMesh) {
+        self.events.borrow_mut().push(Event::Free(mesh));
+    }
+
+    fn draw(&self, mesh: &RecordedMesh, model_view: Matrix4<GLfloat>) {
+        self.events.borrow_mut().push(Event::Draw(*mesh, model_view));
+    }
+}
@@ -0,0 +1,152 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use crate::archive::{ArchiveWriter, Chunk};
+use crate::backend::{MeshBackend, NullBackend};
+use crate::error::Error;
+use crate::field;
+use crate::octree::{Octree, Order, Visit};
+
+const USAGE: &str = "usage: universe bake <level> <output> [<min x> <min y> <min z> <max x> <max y> <max z>]";
+
+/// Axis-aligned box limiting where the bake subdivides.
+pub struct Region {
+    pub min: [f64; 3],
+    pub max: [f64; 3],
+}
+
+impl Region {
+    fn intersects(&self, x: f64, y: f64, z: f64, half: f64) -> bool {
+        self.min[0] <= x + half && self.max[0] >= x - half &&
+        self.min[1] <= y + half && self.max[1] >= y - half &&
+        self.min[2] <= z + half && self.max[2] >= z - half
+    }
+}
+
+/// Entry point for `universe bake`, run without a window or GL context.
+pub fn main(args: &[String]) -> Result<(), Error> {
+    let level: i32 = match args.first().and_then(|level| level.parse().ok()) {
+        Some(level) if level >= 0 => level,
+        _ => return Err(Error::Usage(String::from(USAGE))),
+    };
+    let output = match args.get(1) {
+        Some(output) => output,
+        None => return Err(Error::Usage(String::from(USAGE))),
+    };
+    let region = match args.len() {
+        2 => None,
+        8 => {
+            let mut bounds = [0.0; 6];
+            for (bound, arg) in bounds.iter_mut().zip(&args[2..]) {
+                *bound = arg.parse().map_err(|_| Error::Usage(String::from(USAGE)))?;
+            }
+            Some(Region { min: [bounds[0], bounds[1], bounds[2]], max: [bounds[3], bounds[4], bounds[5]] })
+        }
+        _ => return Err(Error::Usage(String::from(USAGE))),
+    };
+
+    let file = BufWriter::new(File::create(output)?);
+    let mut archive = ArchiveWriter::new(file)?;
+    let mut octree = Octree::with_backend(NullBackend, field::planet);
+    let count = bake(&mut octree, level, region.as_ref(), &mut archive)?;
+    archive.finish()?;
+    println!("Baked {} chunks to {}", count.written, output);
+    if count.failed > 0 {
+        eprintln!("{} chunks failed to generate and were left out", count.failed);
+    }
+    print!("{}", octree.metrics());
+    Ok(())
+}
+
+/// Chunks `bake` wrote, and those it left out because they failed to
+/// generate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BakeCount {
+    pub written: usize,
+    pub failed: usize,
+}
+
+/// Subdivides `octree` down to `level` (only inside `region`, if given) and
+/// writes every node's mesh to `archive`. Chunks that fail to generate are
+/// reported and skipped rather than ending the bake.
+pub fn bake<B: MeshBackend, W: std::io::Write>(octree: &mut Octree<B>, level: i32, region: Option<&Region>, archive: &mut ArchiveWriter<W>) -> Result<BakeCount, Error> {
+    // Pre-order visits the children created on the way down, so one pass
+    // reaches every level
+    octree.visit(Order::PreOrder, |node, info, view| {
+        let (x, y, z) = view.center;
+        let inside = match region {
+            Some(region) => region.intersects(x, y, z, 0.5 * view.size),
+            None => true,
+        };
+        if view.level < level && inside {
+            node.create_children(info, view.key, view.level, x, y, z);
+            Visit::Continue
+        } else {
+            Visit::SkipChildren
+        }
+    });
+
+    let total = octree.nodes(Order::PreOrder).count();
+    let mut count = BakeCount::default();
+
+    for done in 1..=total {
+        octree.retry_deferred();
+        let result = match octree.next_result() {
+            Some(result) => result,
+            None => return Err(Error::Archive(String::from("worker stopped before the bake finished"))),
+        };
+        match result.data {
+            Ok(data) => {
+                archive.write(&Chunk {
+                    path: result.key.path(),
+                    level: result.level,
+                    x: result.x,
+                    y: result.y,
+                    z: result.z,
+                    data,
+                })?;
+                count.written += 1;
+            }
+            Err(err) => {
+                eprintln!("chunk {:?} failed: {}", result.key.path(), err);
+                count.failed += 1;
+            }
+        }
+        if done % 1000 == 0 || done == total {
+            println!("{}/{} chunks", done, total);
+        }
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::archive::ArchiveReader;
+    use crate::worker::Worker;
+
+    #[test]
+    fn skips_failed_chunks() {
+        // Only the root and the children on the +x side sample past 0.2
+        let worker = Worker::with_threads(1, |x, y, z| {
+            if x > 0.2 {
+                panic!("bad field");
+            }
+            (x * x + y * y + z * z).sqrt() - 0.3
+        });
+        let mut octree = Octree::with_worker(NullBackend, worker);
+        let mut archive = ArchiveWriter::new(Vec::new()).unwrap();
+        let count = bake(&mut octree, 1, None, &mut archive).unwrap();
+        assert!(count == BakeCount { written: 4, failed: 5 });
+
+        let mut reader = ArchiveReader::new(Cursor::new(archive.finish().unwrap())).unwrap();
+        let mut chunks = 0;
+        while let Some(chunk) = reader.read().unwrap() {
+            assert!(chunk.level == 1 && chunk.x < 0.0);
+            chunks += 1;
+        }
+        assert!(chunks == 4);
+    }
+}
@@ -0,0 +1,27 @@
+use std::os::unix::net::UnixListener;
+use universe::error::Error;
+use universe::field;
+use universe::material;
+use universe::remote;
+
+const USAGE: &str = "Usage: universe-worker <socket>";
+
+fn main() {
+    if let Err(err) = run(&std::env::args().skip(1).collect::<Vec<_>>()) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), Error> {
+    let socket = match args {
+        [socket] => socket,
+        _ => return Err(Error::Usage(String::from(USAGE))),
+    };
+    // A stale socket left behind by a previous run would make bind fail
+    let _ = std::fs::remove_file(socket);
+    let listener = UnixListener::bind(socket)?;
+    eprintln!("Serving meshes on {}", socket);
+    let (scalar_field, material_field) = material::split(field::planet_with_materials);
+    remote::serve(listener, scalar_field, Some(material_field))
+}
@@ -0,0 +1,737 @@
+#![allow(dead_code)]
+
+use std::ops::Range;
+use cgmath::{Vector3, Matrix4};
+use crate::backend::{MeshBackend, GlBackend};
+use crate::error::Error;
+use crate::geometry::Vertex;
+use crate::isosurface;
+use crate::job::{Job, JobContext};
+use crate::material;
+use crate::metrics::WorkerMetrics;
+use crate::octree::{self, NodeKey, OctreeInfo, UploadBudget, Uploads};
+use crate::queue::{self, Metric};
+use crate::worker::{CancelStats, Pool, Task, Worker};
+
+/// Samples per tile edge for heightmap surfaces.
+const GRID: i32 = 16;
+
+/// A face of the cube that gets projected onto the sphere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Face {
+    PositiveX,
+    NegativeX,
+    PositiveY,
+    NegativeY,
+    PositiveZ,
+    NegativeZ,
+}
+
+impl Face {
+    pub const ALL: [Face; 6] = [
+        Face::PositiveX,
+        Face::NegativeX,
+        Face::PositiveY,
+        Face::NegativeY,
+        Face::PositiveZ,
+        Face::NegativeZ,
+    ];
+
+    #[inline]
+    pub fn index(self) -> usize {
+        Face::ALL.iter().position(|&face| face == self).unwrap()
+    }
+
+    /// Outward normal and the `u`, `v` tangents, with `u × v` pointing out so
+    /// meshes keep their winding when mapped onto the face.
+    #[inline]
+    fn axes(self) -> ([f64; 3], [f64; 3], [f64; 3]) {
+        match self {
+            Face::PositiveX => ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+            Face::NegativeX => ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+            Face::PositiveY => ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]),
+            Face::NegativeY => ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            Face::PositiveZ => ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+            Face::NegativeZ => ([0.0, 0.0, -1.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0]),
+        }
+    }
+
+    /// Unit direction through the point `(u, v)` of the face, with `u` and
+    /// `v` spanning [-1, 1].
+    #[inline]
+    pub fn direction(self, u: f64, v: f64) -> (f64, f64, f64) {
+        let (n, t_u, t_v) = self.axes();
+        let x = n[0] + u * t_u[0] + v * t_v[0];
+        let y = n[1] + u * t_u[1] + v * t_v[1];
+        let z = n[2] + u * t_u[2] + v * t_v[2];
+        let l = (x * x + y * y + z * z).sqrt();
+        (x / l, y / l, z / l)
+    }
+}
+
+/// Identifies a tile by its face, level and integer coordinates within that
+/// level, like `NodeKey` does for octree nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TileKey {
+    pub face: Face,
+    pub level: i32,
+    pub x: i64,
+    pub y: i64,
+}
+
+impl TileKey {
+    #[inline]
+    pub fn root(face: Face) -> TileKey {
+        TileKey { face, level: 0, x: 0, y: 0 }
+    }
+
+    /// Child `index`: bit 1 set for the upper half along `u`, bit 0 along `v`.
+    #[inline]
+    pub fn child(&self, index: usize) -> TileKey {
+        TileKey {
+            face: self.face,
+            level: self.level + 1,
+            x: self.x * 2 + ((index >> 1) & 1) as i64,
+            y: self.y * 2 + (index & 1) as i64,
+        }
+    }
+
+    /// Index of the child at `level` on the way from the face's root down to
+    /// this tile. `level` must be between 1 and `self.level`.
+    #[inline]
+    pub fn child_index(&self, level: i32) -> usize {
+        let shift = self.level - level;
+        (((self.x >> shift) & 1) << 1 | ((self.y >> shift) & 1)) as usize
+    }
+
+    /// Width of the tile in face coordinates.
+    #[inline]
+    pub fn size(&self) -> f64 {
+        2.0 * octree::level_size(self.level)
+    }
+
+    /// Face coordinates of the tile-local point `(a, b)`, where the tile
+    /// spans [-0.5, 0.5].
+    #[inline]
+    pub fn face_coordinates(&self, a: f64, b: f64) -> (f64, f64) {
+        let size = self.size();
+        ((self.x as f64 + 0.5 + a) * size - 1.0, (self.y as f64 + 0.5 + b) * size - 1.0)
+    }
+
+    /// Unit direction through the tile-local point `(a, b)`.
+    #[inline]
+    pub fn direction(&self, a: f64, b: f64) -> (f64, f64, f64) {
+        let (u, v) = self.face_coordinates(a, b);
+        self.face.direction(u, v)
+    }
+
+    /// Centre of the tile projected onto a sphere of `radius`. Tile meshes
+    /// are relative to this point.
+    #[inline]
+    pub fn center(&self, radius: f64) -> (f64, f64, f64) {
+        let (x, y, z) = self.direction(0.0, 0.0);
+        (x * radius, y * radius, z * radius)
+    }
+
+    /// The key tiles travel through the worker with, the face in place of
+    /// `z`.
+    #[inline]
+    pub fn node_key(&self) -> NodeKey {
+        NodeKey { level: self.level, x: self.x, y: self.y, z: self.face.index() as i64 }
+    }
+
+    #[inline]
+    pub fn from_node_key(key: NodeKey) -> Option<TileKey> {
+        let face = *Face::ALL.get(key.z as usize)?;
+        Some(TileKey { face, level: key.level, x: key.x, y: key.y })
+    }
+}
+
+/// What the tiles of a cube-sphere mesh.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Surface {
+    /// The isosurface of the scalar field between two radii.
+    Shell { inner: f64, outer: f64 },
+    /// A sphere of `radius` displaced outwards by the scalar field, which is
+    /// sampled on the unit sphere and returns heights (and materials, if it
+    /// has them).
+    Heightmap { radius: f64 },
+}
+
+impl Surface {
+    /// Radius tile centres are projected to.
+    #[inline]
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Surface::Shell { inner, outer } => (inner + outer) / 2.0,
+            Surface::Heightmap { radius } => radius,
+        }
+    }
+}
+
+/// Meshes cube-sphere tiles. Workers feeding a `CubeSphere` need this as
+/// their mesher, see `worker`.
+pub struct TileMesher {
+    pub surface: Surface,
+}
+
+impl Job for TileMesher {
+    type Output = Vec<Vertex>;
+
+    fn run(&self, context: &JobContext) -> Vec<Vertex> {
+        let key = TileKey::from_node_key(context.key).expect("Not a cube-sphere tile");
+        match self.surface {
+            Surface::Shell { inner, outer } => shell(context, key, inner, outer),
+            Surface::Heightmap { radius } => heightmap(context, key, radius),
+        }
+    }
+}
+
+/// Meshes the field inside boxes bent around the sphere: tile-local `a`, `b`
+/// run along the face and `c` outwards. The shell is cut into radial bands
+/// about as deep as the level's tiles are wide (so cells stay roughly cubic)
+/// and the same for every tile of a level, and a tile meshes the bands the
+/// surface crosses along its corner, edge and centre rays. Those rays are
+/// shared with its neighbours, so both sides of an edge mesh the same cells.
+fn shell(context: &JobContext, key: TileKey, inner: f64, outer: f64) -> Vec<Vertex> {
+    let (depth, bands) = shell_bands(context, key, inner, outer);
+    let (c_x, c_y, c_z) = key.center((inner + outer) / 2.0);
+    let epsilon = depth / 64.0;
+
+    let mut data = Vec::<Vertex>::new();
+    for band in bands {
+        let middle = inner + (band as f64 + 0.5) * depth;
+        let map = |a: f64, b: f64, c: f64| {
+            let (x, y, z) = key.direction(a, b);
+            let r = middle + c * depth;
+            (x * r, y * r, z * r)
+        };
+
+        let mut band = isosurface::with_materials(&|a, b, c| {
+            let (x, y, z) = map(a, b, c);
+            context.sample_with_material(x, y, z)
+        });
+        for vertex in band.iter_mut() {
+            let (x, y, z) = map(f64::from(vertex.position[0]), f64::from(vertex.position[1]), f64::from(vertex.position[2]));
+            let n_x = context.sample(x + epsilon, y, z) - context.sample(x - epsilon, y, z);
+            let n_y = context.sample(x, y + epsilon, z) - context.sample(x, y - epsilon, z);
+            let n_z = context.sample(x, y, z + epsilon) - context.sample(x, y, z - epsilon);
+            let l = (n_x.powi(2) + n_y.powi(2) + n_z.powi(2)).sqrt();
+            vertex.position = [(x - c_x) as f32, (y - c_y) as f32, (z - c_z) as f32];
+            if l > 0.0 {
+                vertex.normal = [(n_x / l) as f32, (n_y / l) as f32, (n_z / l) as f32];
+            }
+            let normal = [f64::from(vertex.normal[0]), f64::from(vertex.normal[1]), f64::from(vertex.normal[2])];
+            let (uv, tangent) = isosurface::texture_frame([c_x, c_y, c_z], [x - c_x, y - c_y, z - c_z], normal);
+            vertex.uv = uv;
+            vertex.tangent = tangent;
+        }
+        data.append(&mut band);
+    }
+    data
+}
+
+/// Depth of the radial bands at `key`'s level, and the bands the tile meshes:
+/// those within half a band of where the surface crosses the tile's rays.
+fn shell_bands(context: &JobContext, key: TileKey, inner: f64, outer: f64) -> (f64, Range<i64>) {
+    let count = ((outer - inner) / (key.size() * (inner + outer) / 2.0)).ceil().max(1.0);
+    let depth = (outer - inner) / count;
+
+    let mut range: Option<(f64, f64)> = None;
+    for &a in &[-0.5, 0.0, 0.5] {
+        for &b in &[-0.5, 0.0, 0.5] {
+            if let Some(r) = crossing(context, key.direction(a, b), inner, outer, depth / 64.0) {
+                range = Some(range.map_or((r, r), |(low, high)| (low.min(r), high.max(r))));
+            }
+        }
+    }
+    match range {
+        Some((low, high)) => {
+            let first = ((low - depth / 2.0 - inner) / depth).floor().max(0.0);
+            let last = ((high + depth / 2.0 - inner) / depth).floor().min(count - 1.0);
+            (depth, first as i64..last as i64 + 1)
+        }
+        None => (depth, 0..0),
+    }
+}
+
+/// Bisects for the radius where the field turns from inside to outside along
+/// `direction`, if it does between `inner` and `outer`.
+fn crossing(context: &JobContext, (x, y, z): (f64, f64, f64), inner: f64, outer: f64, precision: f64) -> Option<f64> {
+    let (mut low, mut high) = (inner, outer);
+    if context.sample(x * low, y * low, z * low) >= 0.0 || context.sample(x * high, y * high, z * high) < 0.0 {
+        return None;
+    }
+    for _ in 0..64 {
+        if high - low <= precision {
+            break;
+        }
+        let middle = (low + high) / 2.0;
+        if context.sample(x * middle, y * middle, z * middle) < 0.0 {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    Some((low + high) / 2.0)
+}
+
+/// Meshes a `GRID` × `GRID` grid over the tile, displaced by the heights.
+/// Each vertex blends the materials at its own sample and its four
+/// neighbours', which the field returns along with the heights.
+fn heightmap(context: &JobContext, key: TileKey, radius: f64) -> Vec<Vertex> {
+    let (c_x, c_y, c_z) = key.center(radius);
+    let step = 1.0 / f64::from(GRID);
+
+    // One extra sample around the edges, for normals
+    let side = (GRID + 3) as usize;
+    let mut points = Vec::with_capacity(side * side);
+    let mut materials = Vec::with_capacity(side * side);
+    for i in -1..=GRID + 1 {
+        for j in -1..=GRID + 1 {
+            let (x, y, z) = key.direction(f64::from(i) * step - 0.5, f64::from(j) * step - 0.5);
+            let (height, material) = context.sample_with_material(x, y, z);
+            let r = radius + height;
+            points.push([x * r - c_x, y * r - c_y, z * r - c_z]);
+            materials.push(material);
+        }
+    }
+    let index = |i: i32, j: i32| (i + 1) as usize * side + (j + 1) as usize;
+    let point = |i: i32, j: i32| points[index(i, j)];
+
+    let vertex = |i: i32, j: i32| {
+        let (p, u_a, u_b, v_a, v_b) = (point(i, j), point(i + 1, j), point(i - 1, j), point(i, j + 1), point(i, j - 1));
+        let d_u = [u_a[0] - u_b[0], u_a[1] - u_b[1], u_a[2] - u_b[2]];
+        let d_v = [v_a[0] - v_b[0], v_a[1] - v_b[1], v_a[2] - v_b[2]];
+        let n = [
+            d_u[1] * d_v[2] - d_u[2] * d_v[1],
+            d_u[2] * d_v[0] - d_u[0] * d_v[2],
+            d_u[0] * d_v[1] - d_u[1] * d_v[0],
+        ];
+        let l = (n[0].powi(2) + n[1].powi(2) + n[2].powi(2)).sqrt();
+        let normal = [n[0] / l, n[1] / l, n[2] / l];
+        let (uv, tangent) = isosurface::texture_frame([c_x, c_y, c_z], p, normal);
+        let around = [index(i, j), index(i + 1, j), index(i - 1, j), index(i, j + 1), index(i, j - 1)];
+        Vertex {
+            position: [p[0] as f32, p[1] as f32, p[2] as f32],
+            normal: [normal[0] as f32, normal[1] as f32, normal[2] as f32],
+            uv,
+            material: material::weights(around.iter().map(|&index| materials[index])),
+            tangent,
+        }
+    };
+
+    let mut data = Vec::<Vertex>::with_capacity((GRID * GRID * 6) as usize);
+    for i in 0..GRID {
+        for j in 0..GRID {
+            data.extend_from_slice(&[
+                vertex(i, j), vertex(i + 1, j), vertex(i + 1, j + 1),
+                vertex(i, j), vertex(i + 1, j + 1), vertex(i, j + 1),
+            ]);
+        }
+    }
+    data
+}
+
+/// A worker on `pool` meshing tiles of `surface`, for
+/// `CubeSphere::with_worker`.
+pub fn worker(pool: &Pool, surface: Surface, scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static) -> Worker {
+    pool.worker_with_mesher(scalar_field, TileMesher { surface })
+}
+
+/// A planet as six quadtrees on the faces of a cube projected onto a sphere,
+/// so tiles are only spent on the surface. Fed by a `Worker` and driven
+/// (`walk`, `set_priority`, `update`, `draw`) like `Octree`, with tile
+/// centres in the same space the scalar field is sampled in.
+pub struct CubeSphere<B: MeshBackend = GlBackend> {
+    faces: Vec<QuadNode<B>>,
+    info: CubeSphereInfo<B>,
+    metric: Metric,
+    uploads: Uploads,
+    errors: Vec<(TileKey, Error)>,
+}
+
+/// Called by `CubeSphere::walk` with each tile, its key, level and centre.
+pub type TileCallback<'a, B> = dyn Fn(&mut QuadNode<B>, &CubeSphereInfo<B>, TileKey, i32, f64, f64, f64) + 'a;
+
+pub struct CubeSphereInfo<B: MeshBackend> {
+    pub(crate) tree: OctreeInfo<B>,
+    pub surface: Surface,
+}
+
+impl<B: MeshBackend> CubeSphereInfo<B> {
+    fn task(&self, key: TileKey, epoch: u64) -> Task {
+        let (x, y, z) = key.center(self.surface.radius());
+        Task { x, y, z, ..Task::generate(key.node_key(), epoch) }
+    }
+
+    fn priority(&self, metric: &Metric, key: TileKey) -> f64 {
+        let (x, y, z) = key.center(self.surface.radius());
+        metric(key.level, x, y, z)
+    }
+}
+
+impl CubeSphere {
+    #[inline]
+    pub fn new(surface: Surface, scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static) -> CubeSphere {
+        CubeSphere::with_backend(GlBackend, surface, scalar_field)
+    }
+}
+
+impl<B: MeshBackend> CubeSphere<B> {
+    pub fn with_backend(backend: B, surface: Surface, scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static) -> CubeSphere<B> {
+        CubeSphere::with_worker(backend, surface, worker(&Pool::spawn(), surface, scalar_field))
+    }
+
+    /// Builds a cube-sphere fed by an existing worker, which has to mesh with
+    /// a `TileMesher` for the same surface.
+    pub fn with_worker(backend: B, surface: Surface, worker: Worker) -> CubeSphere<B> {
+        let info = CubeSphereInfo { tree: OctreeInfo::new(backend, worker), surface };
+        CubeSphere {
+            faces: Face::ALL.iter().map(|&face| QuadNode::new(&info, TileKey::root(face))).collect(),
+            info,
+            metric: queue::by_level(),
+            uploads: Uploads::default(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.info.tree.backend
+    }
+
+    pub fn surface(&self) -> Surface {
+        self.info.surface
+    }
+
+    /// Calls `callback` for every tile, children first, with the tile's
+    /// level and centre.
+    pub fn walk(&mut self, callback: &TileCallback<'_, B>) {
+        for (face, node) in Face::ALL.iter().zip(self.faces.iter_mut()) {
+            node.walk(&self.info, callback, TileKey::root(*face));
+        }
+    }
+
+    /// Draws the tiles; see `Octree::draw` for why `parent_model_view` is
+    /// `f64`.
+    pub fn draw(&mut self, parent_model_view: Matrix4<f64>) {
+        self.walk(&|node, info, _key, _level, x, y, z| {
+            let should_draw = match node.children {
+                Some(ref children) => children.iter().any(|child| child.mesh.is_none()),
+                None => true,
+            };
+            if !should_draw {
+                return;
+            }
+
+            let model_view: Matrix4<f64> = parent_model_view * Matrix4::from_translation(Vector3::new(x, y, z));
+
+            if let Some(ref mesh) = node.mesh {
+                info.tree.backend.draw(mesh, model_view.cast());
+            }
+        });
+    }
+
+    /// Sets the metric deciding which pending tiles are generated, and then
+    /// uploaded, first. It gets tile centres on the sphere.
+    pub fn set_priority(&mut self, metric: Metric) {
+        self.metric = metric.clone();
+        self.info.tree.worker.set_priority(metric);
+    }
+
+    pub fn set_upload_budget(&mut self, budget: UploadBudget) {
+        self.uploads.budget = budget;
+    }
+
+    /// Number of finished tiles waiting to be uploaded.
+    pub fn pending_uploads(&self) -> usize {
+        self.uploads.len()
+    }
+
+    pub fn cancel_stats(&self) -> CancelStats {
+        CancelStats { stale: self.info.tree.stale.get(), ..self.info.tree.worker.cancel_stats() }
+    }
+
+    pub fn metrics(&self) -> WorkerMetrics {
+        self.info.tree.worker.metrics()
+    }
+
+    /// Errors of the tiles that failed to generate since the last call; see
+    /// `Octree::take_errors`.
+    pub fn take_errors(&mut self) -> Vec<(TileKey, Error)> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Re-sends generation requests the worker refused while its queue was
+    /// full.
+    pub fn retry_deferred(&mut self) {
+        let info = &self.info;
+        let faces = &mut self.faces;
+        info.tree.retry_deferred(|key, epoch, send| {
+            let tile = TileKey::from_node_key(key)?;
+            match faces[tile.face.index()].find_mut(tile) {
+                Some(node) if node.epoch == epoch && !node.requested => {
+                    if send {
+                        node.requested = info.tree.worker.try_send(info.task(tile, epoch)).is_ok();
+                    }
+                    Some(node.requested)
+                }
+                _ => None,
+            }
+        });
+    }
+
+    /// Collects finished tiles from the worker and uploads as many as the
+    /// upload budget allows, highest priority first.
+    pub fn update(&mut self) {
+        self.uploads.collect(&self.info.tree.worker);
+        for (key, epoch, error) in self.uploads.take_failed() {
+            let faces = &mut self.faces;
+            match TileKey::from_node_key(key).and_then(|tile| Some((tile, faces[tile.face.index()].find_mut(tile)?))) {
+                Some((tile, node)) if node.epoch == epoch => {
+                    node.failed = true;
+                    self.errors.push((tile, error));
+                }
+                _ => self.info.tree.stale.set(self.info.tree.stale.get() + 1),
+            }
+        }
+        self.retry_deferred();
+
+        let info = &self.info;
+        let metric = &self.metric;
+        let faces = &mut self.faces;
+        self.uploads.upload(|key| match TileKey::from_node_key(key) {
+            Some(key) => info.priority(metric, key),
+            None => f64::INFINITY,
+        }, &info.tree.stale, |upload| {
+            let node = TileKey::from_node_key(upload.key)
+                .and_then(|key| faces[key.face.index()].find_mut(key));
+            match node {
+                Some(node) if node.epoch == upload.epoch => {
+                    let mesh = info.tree.backend.upload(upload.data.as_ref());
+                    if let Some(old) = node.mesh.replace(mesh) {
+                        info.tree.backend.free(old);
+                    }
+                    true
+                }
+                _ => false,
+            }
+        });
+    }
+}
+
+pub struct QuadNode<B: MeshBackend> {
+    pub mesh: Option<B::Mesh>,
+    pub children: Option<Box<[QuadNode<B>; 4]>>,
+    pub epoch: u64,
+    /// Whether the worker accepted this tile's generation task.
+    pub requested: bool,
+    /// Whether the worker failed to generate the mesh.
+    pub failed: bool,
+}
+
+impl<B: MeshBackend> QuadNode<B> {
+    pub fn new(info: &CubeSphereInfo<B>, key: TileKey) -> QuadNode<B> {
+        let epoch = info.tree.next_epoch();
+        let requested = info.tree.request(info.task(key, epoch));
+        QuadNode { mesh: None, children: None, epoch, requested, failed: false }
+    }
+
+    fn walk(&mut self, info: &CubeSphereInfo<B>, callback: &TileCallback<'_, B>, key: TileKey) {
+        if let Some(ref mut children) = self.children {
+            for (index, child) in children.iter_mut().enumerate() {
+                child.walk(info, callback, key.child(index));
+            }
+        }
+
+        let (x, y, z) = key.center(info.surface.radius());
+        callback(self, info, key, key.level, x, y, z);
+    }
+
+    pub fn create_children(&mut self, info: &CubeSphereInfo<B>, key: TileKey) {
+        if self.children.is_none() {
+            self.children = Some(Box::from([
+                QuadNode::new(info, key.child(0)),
+                QuadNode::new(info, key.child(1)),
+                QuadNode::new(info, key.child(2)),
+                QuadNode::new(info, key.child(3)),
+            ]));
+        }
+    }
+
+    pub fn destroy_children(&mut self, info: &CubeSphereInfo<B>, key: TileKey) {
+        if let Some(mut children) = self.children.take() {
+            for (index, child) in children.iter_mut().enumerate() {
+                child.free(info, key.child(index));
+            }
+        }
+    }
+
+    /// Hands this tile's mesh and those of all its descendants back to the
+    /// backend, cancelling generation for any that haven't finished yet.
+    fn free(&mut self, info: &CubeSphereInfo<B>, key: TileKey) {
+        match self.mesh.take() {
+            Some(mesh) => info.tree.backend.free(mesh),
+            None if !self.requested => info.tree.deferred.set(info.tree.deferred.get() - 1),
+            None if self.failed => {}
+            None => {
+                let _ = info.tree.worker.send(Task::cancel(key.node_key(), self.epoch));
+            }
+        }
+        if let Some(mut children) = self.children.take() {
+            for (index, child) in children.iter_mut().enumerate() {
+                child.free(info, key.child(index));
+            }
+        }
+    }
+
+    /// Finds the descendant (or this tile, for its face's root) with `key`.
+    pub fn find_mut(&mut self, key: TileKey) -> Option<&mut QuadNode<B>> {
+        let mut node = self;
+        for level in 1..=key.level {
+            node = match node.children {
+                Some(ref mut children) => &mut children[key.child_index(level)],
+                None => return None,
+            };
+        }
+        Some(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RecordingBackend;
+    use crate::material::Material;
+
+    fn distance(vertex: &Vertex, center: (f64, f64, f64)) -> f64 {
+        let x = f64::from(vertex.position[0]) + center.0;
+        let y = f64::from(vertex.position[1]) + center.1;
+        let z = f64::from(vertex.position[2]) + center.2;
+        (x * x + y * y + z * z).sqrt()
+    }
+
+    #[test]
+    fn tile_keys() {
+        let key = TileKey::root(Face::NegativeY).child(2).child(1).child(3);
+        assert!((1..=3).map(|level| key.child_index(level)).collect::<Vec<_>>() == vec![2, 1, 3]);
+        assert!(TileKey::from_node_key(key.node_key()) == Some(key));
+
+        // Adjacent faces meet along their edges
+        let edge = Face::PositiveX.direction(1.0, 0.0);
+        let other = Face::PositiveY.direction(0.0, 1.0);
+        assert!((edge.0 - other.0).abs() < 1e-12 && (edge.1 - other.1).abs() < 1e-12 && (edge.2 - other.2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn meshes_on_sphere() {
+        let field = |x: f64, y: f64, z: f64| (x * x + y * y + z * z).sqrt() - 0.4;
+        let key = TileKey::root(Face::PositiveZ).child(1);
+
+        let surface = Surface::Shell { inner: 0.3, outer: 0.5 };
+        let data = TileMesher { surface }.run(&JobContext::new(key.node_key(), &field));
+        assert!(!data.is_empty());
+        let center = key.center(surface.radius());
+        assert!(data.iter().all(|vertex| (distance(vertex, center) - 0.4).abs() < 0.02));
+
+        let surface = Surface::Heightmap { radius: 0.4 };
+        let data = TileMesher { surface }.run(&JobContext::new(key.node_key(), &|_x, _y, _z| 0.01));
+        assert!(data.len() == (GRID * GRID * 6) as usize);
+        let center = key.center(surface.radius());
+        assert!(data.iter().all(|vertex| (distance(vertex, center) - 0.41).abs() < 1e-5));
+    }
+
+    #[test]
+    fn blends_materials() {
+        let key = TileKey::root(Face::PositiveZ);
+        let ground = |x: f64| if x < 0.0 { Material::Sand } else { Material::Snow };
+        let blends = |data: &[Vertex]| {
+            assert!(!data.is_empty());
+            assert!(data.iter().all(|vertex| (vertex.material.iter().sum::<f32>() - 1.0).abs() < 1e-6));
+            data.iter().any(|vertex| vertex.material[Material::Sand.index()] > 0.0 && vertex.material[Material::Snow.index()] > 0.0)
+        };
+
+        let sphere = move |x: f64, y: f64, z: f64| ((x * x + y * y + z * z).sqrt() - 0.4, ground(x));
+        let (scalar_field, material_field) = material::split(sphere);
+        let context = JobContext::new(key.node_key(), &*scalar_field).with_materials(Some(&*material_field));
+        assert!(blends(&TileMesher { surface: Surface::Shell { inner: 0.3, outer: 0.5 } }.run(&context)));
+
+        let (scalar_field, material_field) = material::split(move |x, _y, _z| (0.01, ground(x)));
+        let context = JobContext::new(key.node_key(), &*scalar_field).with_materials(Some(&*material_field));
+        assert!(blends(&TileMesher { surface: Surface::Heightmap { radius: 0.4 } }.run(&context)));
+    }
+
+    #[test]
+    fn neighbours_share_bands() {
+        // Steep enough that neighbouring tiles cross the surface at different depths
+        let field = |x: f64, y: f64, z: f64| {
+            let r = (x * x + y * y + z * z).sqrt();
+            r - 0.4 - 0.05 * (40.0 * x / r).sin()
+        };
+        let (inner, outer) = (0.3, 0.5);
+        let left = TileKey { face: Face::PositiveZ, level: 5, x: 16, y: 16 };
+        let right = TileKey { x: 17, ..left };
+
+        let (depth, left_bands) = shell_bands(&JobContext::new(left.node_key(), &field), left, inner, outer);
+        let (_, right_bands) = shell_bands(&JobContext::new(right.node_key(), &field), right, inner, outer);
+        assert!(left_bands != right_bands);
+        for &b in &[-0.5, 0.0, 0.5] {
+            let context = JobContext::new(left.node_key(), &field);
+            let r = crossing(&context, left.direction(0.5, b), inner, outer, depth / 64.0).unwrap();
+            let band = ((r - inner) / depth).floor() as i64;
+            assert!(left_bands.contains(&band) && right_bands.contains(&band));
+        }
+    }
+
+    #[test]
+    fn heightmap_textures_line_up() {
+        let field = |x: f64, _y: f64, _z: f64| 0.01 * x;
+        let surface = Surface::Heightmap { radius: 0.4 };
+        let left = TileKey { face: Face::PositiveZ, level: 3, x: 3, y: 4 };
+        let right = TileKey { x: 4, ..left };
+        let world = |key: TileKey| {
+            let center = key.center(surface.radius());
+            TileMesher { surface }.run(&JobContext::new(key.node_key(), &field)).into_iter().map(move |vertex| {
+                let p = vertex.position;
+                ([f64::from(p[0]) + center.0, f64::from(p[1]) + center.1, f64::from(p[2]) + center.2], vertex.uv)
+            }).collect::<Vec<_>>()
+        };
+
+        // Vertices along the shared edge get the same coordinates from both
+        // tiles, up to whole repeats
+        let (left, right) = (world(left), world(right));
+        let mut shared = 0;
+        for (p, uv) in &left {
+            for (q, other) in &right {
+                if (0..3).all(|i| (p[i] - q[i]).abs() < 1e-6) {
+                    for i in 0..2 {
+                        let d = f64::from(uv[i] - other[i]);
+                        assert!((d - d.round()).abs() < 1e-3);
+                    }
+                    shared += 1;
+                }
+            }
+        }
+        assert!(shared > 0);
+    }
+
+    #[test]
+    fn upload_and_subdivide() {
+        let surface = Surface::Heightmap { radius: 0.4 };
+        let worker = worker(&Pool::synchronous(), surface, |_x, _y, _z| 0.0);
+        let mut sphere = CubeSphere::with_worker(RecordingBackend::new(), surface, worker);
+        sphere.update();
+        assert!(sphere.backend().uploads() == 6);
+
+        sphere.walk(&|node, info, key, _level, _x, _y, _z| {
+            if key.face == Face::PositiveX && key.level == 0 {
+                node.create_children(info, key);
+            }
+        });
+        sphere.update();
+        assert!(sphere.backend().uploads() == 10);
+
+        sphere.walk(&|node, info, key, _level, _x, _y, _z| node.destroy_children(info, key));
+        assert!(sphere.backend().resident() == 6);
+    }
+}
@@ -0,0 +1,473 @@
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use cgmath::Vector3;
+use crate::archive::{read_f32, read_i64, read_u32, write_f32, write_i64, write_u32};
+use crate::backend::MeshBackend;
+use crate::edit::Bounds;
+use crate::error::Error;
+use crate::octree::{level_size, NodeKey, Octree, MAX_LEVEL};
+
+const MAGIC: &[u8; 4] = b"UVDL";
+const VERSION: u32 = 1;
+
+/// Cells along each axis of a chunk. Deltas are stored at the cell corners,
+/// so neighbouring chunks share (and both store) their boundary samples.
+pub const RESOLUTION: usize = 16;
+const SAMPLES: usize = RESOLUTION + 1;
+
+/// A sample changed by `DeltaStore::modify`: its chunk, its index within it
+/// and its values before and after.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SampleChange {
+    pub chunk: NodeKey,
+    pub index: usize,
+    pub old: f32,
+    pub new: f32,
+}
+
+const SPARSE: u8 = 0;
+const DENSE: u8 = 1;
+
+struct DeltaChunk {
+    values: Vec<f32>,
+    /// Changed since the last save.
+    dirty: bool,
+}
+
+impl DeltaChunk {
+    fn new() -> DeltaChunk {
+        DeltaChunk { values: vec![0.0; SAMPLES * SAMPLES * SAMPLES], dirty: false }
+    }
+
+    #[inline]
+    fn index(i: usize, j: usize, k: usize) -> usize {
+        (i * SAMPLES + j) * SAMPLES + k
+    }
+}
+
+/// Sparse density offsets added to a scalar field, stored in chunks the size
+/// of the octree nodes at one level and created only where something was
+/// changed. Values between samples are interpolated trilinearly.
+///
+/// Saved files hold a header (little endian: magic, version, level,
+/// resolution) followed by chunk records; a chunk recorded more than once
+/// takes its last record, so `save_dirty` can append just what changed.
+pub struct DeltaStore {
+    level: i32,
+    chunks: RwLock<HashMap<NodeKey, DeltaChunk>>,
+}
+
+impl DeltaStore {
+    /// A store with chunks the size of nodes at `level`.
+    pub fn new(level: i32) -> Arc<DeltaStore> {
+        Arc::new(DeltaStore { level, chunks: RwLock::new(HashMap::new()) })
+    }
+
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    /// `base` plus this store's deltas, to build a worker with.
+    pub fn over(self: &Arc<Self>, base: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static) -> impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static {
+        let store = self.clone();
+        move |x, y, z| base(x, y, z) + store.sample(x, y, z)
+    }
+
+    /// The chunk containing `(x, y, z)`.
+    fn chunk_key(&self, x: f64, y: f64, z: f64) -> NodeKey {
+        let size = level_size(self.level);
+        let cell = |p: f64| ((p + 0.5) / size).floor() as i64;
+        NodeKey { level: self.level, x: cell(x), y: cell(y), z: cell(z) }
+    }
+
+    /// Corner of the chunk `key` with the lowest coordinates.
+    fn chunk_min(&self, key: NodeKey) -> Vector3<f64> {
+        let size = level_size(self.level);
+        Vector3::new(key.x as f64 * size - 0.5, key.y as f64 * size - 0.5, key.z as f64 * size - 0.5)
+    }
+
+    /// The delta at `(x, y, z)`; zero outside every chunk.
+    pub fn sample(&self, x: f64, y: f64, z: f64) -> f64 {
+        let key = self.chunk_key(x, y, z);
+        let chunks = self.chunks.read().unwrap();
+        let chunk = match chunks.get(&key) {
+            Some(chunk) => chunk,
+            None => return 0.0,
+        };
+
+        let min = self.chunk_min(key);
+        let scale = RESOLUTION as f64 / level_size(self.level);
+        let cell = |p: f64, min: f64| {
+            let local = ((p - min) * scale).clamp(0.0, RESOLUTION as f64);
+            let index = (local.floor() as usize).min(RESOLUTION - 1);
+            (index, local - index as f64)
+        };
+        let ((i, fx), (j, fy), (k, fz)) = (cell(x, min.x), cell(y, min.y), cell(z, min.z));
+
+        let mut value = 0.0;
+        for (di, wx) in [(0, 1.0 - fx), (1, fx)] {
+            for (dj, wy) in [(0, 1.0 - fy), (1, fy)] {
+                for (dk, wz) in [(0, 1.0 - fz), (1, fz)] {
+                    value += wx * wy * wz * chunk.values[DeltaChunk::index(i + di, j + dj, k + dk)] as f64;
+                }
+            }
+        }
+        value
+    }
+
+    /// Adds `delta` at every sample inside `bounds`, creating chunks as
+    /// needed. Returns the region whose interpolated deltas may have changed.
+    pub fn modify(&self, bounds: &Bounds, delta: impl Fn(f64, f64, f64) -> f64) -> Bounds {
+        self.modify_recorded(bounds, delta).0
+    }
+
+    /// `modify`, also returning every sample it changed.
+    pub(crate) fn modify_recorded(&self, bounds: &Bounds, delta: impl Fn(f64, f64, f64) -> f64) -> (Bounds, Vec<SampleChange>) {
+        let size = level_size(self.level);
+        let step = size / RESOLUTION as f64;
+        let (low, high) = (self.chunk_key(bounds.min.x, bounds.min.y, bounds.min.z), self.chunk_key(bounds.max.x, bounds.max.y, bounds.max.z));
+        // Boundary samples are shared with the chunk below
+        let range = |low: i64, high: i64, min: f64| (low - 1..=high).filter(move |&index| (index as f64 + 1.0) * size - 0.5 >= min);
+
+        let mut recorded = Vec::new();
+        let mut chunks = self.chunks.write().unwrap();
+        for x in range(low.x, high.x, bounds.min.x) {
+            for y in range(low.y, high.y, bounds.min.y) {
+                for z in range(low.z, high.z, bounds.min.z) {
+                    let key = NodeKey { level: self.level, x, y, z };
+                    let min = self.chunk_min(key);
+                    let samples = |min: f64, from: f64, to: f64| {
+                        let first = ((from - min) / step).ceil().max(0.0) as usize;
+                        let last = ((to - min) / step).floor().min(RESOLUTION as f64);
+                        if last < 0.0 { first..first } else { first..(last as usize + 1) }
+                    };
+
+                    let mut changes = Vec::new();
+                    for i in samples(min.x, bounds.min.x, bounds.max.x) {
+                        for j in samples(min.y, bounds.min.y, bounds.max.y) {
+                            for k in samples(min.z, bounds.min.z, bounds.max.z) {
+                                let value = delta(min.x + i as f64 * step, min.y + j as f64 * step, min.z + k as f64 * step);
+                                if value != 0.0 {
+                                    changes.push((DeltaChunk::index(i, j, k), value as f32));
+                                }
+                            }
+                        }
+                    }
+                    if changes.is_empty() {
+                        continue;
+                    }
+
+                    let chunk = chunks.entry(key).or_insert_with(DeltaChunk::new);
+                    for (index, value) in changes {
+                        let old = chunk.values[index];
+                        chunk.values[index] += value;
+                        recorded.push(SampleChange { chunk: key, index, old, new: chunk.values[index] });
+                    }
+                    chunk.dirty = true;
+                }
+            }
+        }
+
+        let grow = Vector3::new(step, step, step);
+        (Bounds { min: bounds.min - grow, max: bounds.max + grow }, recorded)
+    }
+
+    /// Takes the difference each of `changes` made away from its sample, or
+    /// adds it back, leaving changes made to the samples since in place.
+    pub(crate) fn revert(&self, changes: &[SampleChange], undo: bool) {
+        let mut chunks = self.chunks.write().unwrap();
+        for change in changes {
+            let chunk = chunks.entry(change.chunk).or_insert_with(DeltaChunk::new);
+            let difference = change.new - change.old;
+            chunk.values[change.index] += if undo { -difference } else { difference };
+            chunk.dirty = true;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Chunks changed since they were last saved.
+    pub fn dirty(&self) -> usize {
+        self.chunks.read().unwrap().values().filter(|chunk| chunk.dirty).count()
+    }
+
+    /// Writes every chunk to a new file at `path`. The file is written next
+    /// to `path` and renamed over it, so a failed save leaves the previous
+    /// one intact.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = Path::new(&temporary);
+
+        let mut file = BufWriter::new(File::create(temporary)?);
+        let result = self.write_header(&mut file).and_then(|()| self.write_snapshot(&mut file, false, |file| {
+            file.get_ref().sync_all()?;
+            std::fs::rename(temporary, path)?;
+            Ok(())
+        }));
+        if result.is_err() {
+            drop(file);
+            let _ = std::fs::remove_file(temporary);
+        }
+        result.map(|_| ())
+    }
+
+    /// Appends the chunks changed since the last save to the file at `path`,
+    /// creating it if needed. Returns how many were written. If appending
+    /// fails, the file is cut back to where it ended, so no partial record is
+    /// left for later appends to follow.
+    pub fn save_dirty<P: AsRef<Path>>(&self, path: P) -> Result<usize, Error> {
+        let path = path.as_ref();
+        let exists = path.exists() && path.metadata()?.len() > 0;
+        if exists {
+            let level = read_header(&mut File::open(path)?)?;
+            if level != self.level {
+                return Err(Error::Archive(format!("delta file has chunks at level {}, not {}", level, self.level)));
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let end = file.metadata()?.len();
+        let mut writer = BufWriter::new(&file);
+        let written = if exists { Ok(()) } else { self.write_header(&mut writer) }
+            .and_then(|()| self.write_snapshot(&mut writer, true, |_| Ok(())));
+        // Whatever is still buffered after an error is dropped, not written
+        let _ = writer.into_parts();
+        if written.is_err() {
+            file.set_len(end)?;
+        }
+        written
+    }
+
+    /// Writes a copy of the chunks (only the dirty ones, if `dirty_only`),
+    /// then calls `finish` to commit the file, and returns how many it wrote.
+    /// The copy is taken, and the chunks marked clean, under the lock, so
+    /// sampling and editing aren't held up by the file; a chunk changed
+    /// meanwhile is dirty again for the next save.
+    fn write_snapshot<W: Write>(&self, file: &mut W, dirty_only: bool, finish: impl FnOnce(&mut W) -> Result<(), Error>) -> Result<usize, Error> {
+        let snapshot: Vec<(NodeKey, DeltaChunk)> = {
+            let mut chunks = self.chunks.write().unwrap();
+            chunks.iter_mut().filter(|(_, chunk)| chunk.dirty || !dirty_only).map(|(key, chunk)| {
+                chunk.dirty = false;
+                (*key, DeltaChunk { values: chunk.values.clone(), dirty: false })
+            }).collect()
+        };
+
+        let result = snapshot.iter().try_for_each(|(key, chunk)| write_chunk(file, *key, chunk))
+            .and_then(|()| file.flush().map_err(Error::from))
+            .and_then(|()| finish(file));
+        if result.is_err() {
+            // Nothing was saved for sure, so the next save has to try again
+            let mut chunks = self.chunks.write().unwrap();
+            for (key, _) in &snapshot {
+                if let Some(chunk) = chunks.get_mut(key) {
+                    chunk.dirty = true;
+                }
+            }
+        }
+        result.map(|()| snapshot.len())
+    }
+
+    /// Reads a store written by `save` or `save_dirty`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Arc<DeltaStore>, Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        let level = read_header(&mut file)?;
+
+        let mut chunks = HashMap::new();
+        while let Some((key, chunk)) = read_chunk(&mut file, level)? {
+            if chunk.values.iter().all(|&value| value == 0.0) {
+                chunks.remove(&key);
+            } else {
+                chunks.insert(key, chunk);
+            }
+        }
+        Ok(Arc::new(DeltaStore { level, chunks: RwLock::new(chunks) }))
+    }
+
+    fn write_header(&self, w: &mut impl Write) -> Result<(), Error> {
+        w.write_all(MAGIC)?;
+        write_u32(w, VERSION)?;
+        write_u32(w, self.level as u32)?;
+        write_u32(w, RESOLUTION as u32)?;
+        Ok(())
+    }
+}
+
+impl<B: MeshBackend> Octree<B> {
+    /// Adds `delta` to `store`, which this octree's worker must be sampling
+    /// through (see `DeltaStore::over`), inside `bounds`, and regenerates the
+    /// nodes that changes.
+    pub fn modify(&mut self, store: &DeltaStore, bounds: &Bounds, delta: impl Fn(f64, f64, f64) -> f64) {
+        let changed = store.modify(bounds, delta);
+        self.invalidate(&changed);
+    }
+}
+
+/// Reads the header and returns the level of the chunks that follow.
+fn read_header(r: &mut impl Read) -> Result<i32, Error> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::Archive(String::from("not a terrain delta file")));
+    }
+    let version = read_u32(r)?;
+    if version != VERSION {
+        return Err(Error::Archive(format!("unsupported delta file version {}", version)));
+    }
+    let level = read_u32(r)?;
+    if level > MAX_LEVEL as u32 {
+        return Err(Error::Archive(format!("delta chunks at level {} are too small", level)));
+    }
+    let resolution = read_u32(r)?;
+    if resolution as usize != RESOLUTION {
+        return Err(Error::Archive(format!("unsupported delta resolution {}", resolution)));
+    }
+    Ok(level as i32)
+}
+
+/// Writes the chunk's key and its values, listing only the non-zero ones
+/// when that is smaller.
+fn write_chunk(w: &mut impl Write, key: NodeKey, chunk: &DeltaChunk) -> Result<(), Error> {
+    write_i64(w, key.x)?;
+    write_i64(w, key.y)?;
+    write_i64(w, key.z)?;
+    let nonzero = chunk.values.iter().filter(|&&value| value != 0.0).count();
+    if nonzero * 2 < chunk.values.len() {
+        w.write_all(&[SPARSE])?;
+        write_u32(w, nonzero as u32)?;
+        for (index, &value) in chunk.values.iter().enumerate().filter(|&(_, &value)| value != 0.0) {
+            write_u32(w, index as u32)?;
+            write_f32(w, value)?;
+        }
+    } else {
+        w.write_all(&[DENSE])?;
+        for &value in &chunk.values {
+            write_f32(w, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the next chunk record, or `None` at the end of the file.
+fn read_chunk(r: &mut impl Read, level: i32) -> Result<Option<(NodeKey, DeltaChunk)>, Error> {
+    let x = match read_i64(r) {
+        Ok(x) => x,
+        Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(Error::from(err)),
+    };
+    let y = read_i64(r)?;
+    let z = read_i64(r)?;
+
+    let mut chunk = DeltaChunk::new();
+    let mut encoding = [0u8];
+    r.read_exact(&mut encoding)?;
+    match encoding[0] {
+        SPARSE => {
+            for _ in 0..read_u32(r)? {
+                let index = read_u32(r)? as usize;
+                let value = read_f32(r)?;
+                match chunk.values.get_mut(index) {
+                    Some(slot) => *slot = value,
+                    None => return Err(Error::Archive(format!("delta sample {} out of range", index))),
+                }
+            }
+        }
+        DENSE => {
+            for value in chunk.values.iter_mut() {
+                *value = read_f32(r)?;
+            }
+        }
+        encoding => return Err(Error::Archive(format!("unknown delta chunk encoding {}", encoding))),
+    }
+    Ok(Some((NodeKey { level, x, y, z }, chunk)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RecordingBackend;
+    use crate::worker::Worker;
+
+    fn bounds(min: f64, max: f64) -> Bounds {
+        Bounds { min: Vector3::new(min, min, min), max: Vector3::new(max, max, max) }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let store = DeltaStore::new(3);
+        // Straddles chunk boundaries at 0
+        store.modify(&bounds(-0.05, 0.05), |_, _, _| 1.0);
+        assert!(store.len() == 8);
+        assert!(store.sample(0.0, 0.0, 0.0) == 1.0);
+        assert!((store.sample(0.01, -0.02, 0.03) - 1.0).abs() < 1e-9);
+        assert!(store.sample(0.3, 0.3, 0.3) == 0.0);
+        // Halfway between the last sample inside and the first outside
+        let step = level_size(3) / RESOLUTION as f64;
+        let edge = (0.05 / step).floor() * step;
+        assert!((store.sample(edge + 0.5 * step, 0.0, 0.0) - 0.5).abs() < 1e-9);
+
+        let path = std::env::temp_dir().join(format!("universe-delta-{}.bin", std::process::id()));
+        store.save(&path).unwrap();
+        assert!(store.dirty() == 0);
+        let full = path.metadata().unwrap().len();
+
+        // Only the chunk changed since is appended
+        store.modify(&bounds(0.2, 0.24), |_, _, _| -2.0);
+        assert!(store.dirty() == 1);
+        assert!(store.save_dirty(&path).unwrap() == 1);
+        assert!(store.save_dirty(&path).unwrap() == 0);
+        assert!(path.metadata().unwrap().len() < full + full / 4);
+
+        let loaded = DeltaStore::load(&path).unwrap();
+        assert!(loaded.level() == 3);
+        assert!(loaded.len() == 9);
+        assert!((loaded.sample(0.22, 0.22, 0.22) + 2.0).abs() < 1e-9);
+        assert!((loaded.sample(0.01, -0.02, 0.03) - 1.0).abs() < 1e-9);
+
+        // Appending needs the same level and resolution
+        std::fs::write(&path, [&MAGIC[..], &[1, 0, 0, 0, 3, 0, 0, 0, 8, 0, 0, 0]].concat()).unwrap();
+        assert!(store.save_dirty(&path).is_err());
+        std::fs::write(&path, [&MAGIC[..], &[1, 0, 0, 0, 99, 0, 0, 0, 16, 0, 0, 0]].concat()).unwrap();
+        assert!(DeltaStore::load(&path).is_err());
+
+        // Saving replaces the file whole, leaving nothing else behind
+        store.save(&path).unwrap();
+        assert!(DeltaStore::load(&path).unwrap().len() == 9);
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        assert!(!Path::new(&temporary).exists());
+
+        std::fs::write(&path, b"UVDL\x02\0\0\0").unwrap();
+        assert!(DeltaStore::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn regenerates_modified_nodes() {
+        let store = DeltaStore::new(4);
+        let mut octree = Octree::with_worker(RecordingBackend::new(), Worker::synchronous(store.over(|x, y, z| {
+            (x * x + y * y + z * z).sqrt() - 0.4
+        })));
+        octree.update();
+
+        // Digging into the surface around +x
+        octree.modify(&store, &Bounds { min: Vector3::new(0.3, -0.05, -0.05), max: Vector3::new(0.5, 0.05, 0.05) }, |_, _, _| 0.5);
+        octree.update();
+        assert!(octree.backend().uploads() == 2);
+        let hit = octree.raycast(Vector3::new(2.0, 0.0, 0.0), Vector3::new(-1.0, 0.0, 0.0), 10.0).unwrap();
+        assert!(hit.point.x < 0.31);
+    }
+}
@@ -0,0 +1,327 @@
+#![allow(dead_code)]
+
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use cgmath::{InnerSpace, Vector3};
+use crate::backend::MeshBackend;
+use crate::isosurface;
+use crate::octree::{NodeKey, Octree, Order, Visit};
+
+/// Axis-aligned box in world coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: Vector3<f64>,
+    pub max: Vector3<f64>,
+}
+
+impl Bounds {
+    #[inline]
+    pub fn contains(&self, x: f64, y: f64, z: f64) -> bool {
+        x >= self.min.x && x <= self.max.x &&
+        y >= self.min.y && y <= self.max.y &&
+        z >= self.min.z && z <= self.max.z
+    }
+
+    /// Distance from `point` to the nearest point of these bounds, zero
+    /// inside them.
+    pub fn distance(&self, point: Vector3<f64>) -> f64 {
+        let axis = |p: f64, min: f64, max: f64| (min - p).max(p - max).max(0.0);
+        Vector3::new(
+            axis(point.x, self.min.x, self.max.x),
+            axis(point.y, self.min.y, self.max.y),
+            axis(point.z, self.min.z, self.max.z),
+        ).magnitude()
+    }
+
+    /// Whether these bounds come within `margin` node sizes of the node `key`.
+    pub fn intersects_node(&self, key: NodeKey, margin: f64) -> bool {
+        let (x, y, z) = key.center();
+        let half = (0.5 + margin) * key.size();
+        self.min.x <= x + half && self.max.x >= x - half &&
+        self.min.y <= y + half && self.max.y >= y - half &&
+        self.min.z <= z + half && self.max.z >= z - half
+    }
+
+    pub fn union(&self, other: &Bounds) -> Bounds {
+        Bounds {
+            min: Vector3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Vector3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Sphere { center: Vector3<f64>, radius: f64 },
+    /// Axis-aligned box.
+    Box { center: Vector3<f64>, half_extents: Vector3<f64> },
+}
+
+impl Shape {
+    /// Signed distance from the shape's surface, negative inside.
+    pub fn distance(&self, point: Vector3<f64>) -> f64 {
+        match *self {
+            Shape::Sphere { center, radius } => (point - center).magnitude() - radius,
+            Shape::Box { center, half_extents } => {
+                let offset = point - center;
+                let q = Vector3::new(offset.x.abs(), offset.y.abs(), offset.z.abs()) - half_extents;
+                let outside = Vector3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).magnitude();
+                outside + q.x.max(q.y).max(q.z).min(0.0)
+            }
+        }
+    }
+
+    pub fn bounds(&self) -> Bounds {
+        let (center, half) = match *self {
+            Shape::Sphere { center, radius } => (center, Vector3::new(radius, radius, radius)),
+            Shape::Box { center, half_extents } => (center, half_extents),
+        };
+        Bounds { min: center - half, max: center + half }
+    }
+}
+
+/// How a brush combines with the field beneath it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    /// Fills the shape in.
+    Add,
+    /// Carves the shape out.
+    Subtract,
+    /// Like `Add`, rounding the seam over a distance of about `blend`.
+    SmoothAdd { blend: f64 },
+    /// Like `Subtract`, rounding the seam over a distance of about `blend`.
+    SmoothSubtract { blend: f64 },
+}
+
+/// One CSG edit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Brush {
+    pub shape: Shape,
+    pub operation: Operation,
+}
+
+impl Brush {
+    /// Where the brush can change the sign of the field: the shape, grown by
+    /// the blend distance of smooth operations. Outside them the brush can
+    /// still bring the field closer to zero, but never across it.
+    pub fn bounds(&self) -> Bounds {
+        let blend = match self.operation {
+            Operation::SmoothAdd { blend } | Operation::SmoothSubtract { blend } => blend.max(0.0),
+            Operation::Add | Operation::Subtract => 0.0,
+        };
+        let bounds = self.shape.bounds();
+        let grow = Vector3::new(blend, blend, blend);
+        Bounds { min: bounds.min - grow, max: bounds.max + grow }
+    }
+
+    /// Whether the brush leaves `value` as it is at a point `gap` or more away
+    /// from its shape, without evaluating the shape.
+    pub fn leaves(&self, value: f64, gap: f64) -> bool {
+        match self.operation {
+            Operation::Add => gap >= value,
+            Operation::Subtract => gap >= -value,
+            Operation::SmoothAdd { blend } => gap - value >= blend,
+            Operation::SmoothSubtract { blend } => gap + value >= blend,
+        }
+    }
+
+    /// Applies the brush to the field's `value` at `point`.
+    pub fn apply(&self, value: f64, point: Vector3<f64>) -> f64 {
+        let distance = self.shape.distance(point);
+        match self.operation {
+            Operation::Add => value.min(distance),
+            Operation::Subtract => value.max(-distance),
+            Operation::SmoothAdd { blend } => smooth_min(value, distance, blend),
+            Operation::SmoothSubtract { blend } => -smooth_min(-value, distance, blend),
+        }
+    }
+}
+
+/// Polynomial smooth minimum, equal to `a.min(b)` where the two are more than
+/// `k` apart.
+fn smooth_min(a: f64, b: f64, k: f64) -> f64 {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+    let h = (k - (a - b).abs()).max(0.0) / k;
+    a.min(b) - h * h * k * 0.25
+}
+
+/// Identifies a brush within its `EditLayer`. Later brushes get larger ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BrushId(u64);
+
+/// CSG brushes overlaid on a scalar field, applied in order. Shared between
+/// the octree, which adds brushes, and the worker's threads, which sample
+/// the field through `over`.
+#[derive(Default)]
+pub struct EditLayer {
+    /// In the order they apply, which is also the order of their ids.
+    brushes: RwLock<Vec<(BrushId, Brush, Bounds)>>,
+    next_id: AtomicU64,
+}
+
+impl EditLayer {
+    pub fn new() -> Arc<EditLayer> {
+        Arc::new(EditLayer::default())
+    }
+
+    /// `base` with this layer's brushes applied, to build a worker with.
+    pub fn over(self: &Arc<Self>, base: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static) -> impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static {
+        let layer = self.clone();
+        move |x, y, z| layer.sample(&base, x, y, z)
+    }
+
+    /// Applies the brushes to `base` at `(x, y, z)`. A brush is only
+    /// evaluated where its distance from the point, at least the distance to
+    /// its bounds, might change the value.
+    pub fn sample(&self, base: &dyn Fn(f64, f64, f64) -> f64, x: f64, y: f64, z: f64) -> f64 {
+        let point = Vector3::new(x, y, z);
+        self.brushes.read().unwrap().iter().fold(base(x, y, z), |value, (_, brush, bounds)| {
+            if !bounds.contains(x, y, z) && brush.leaves(value, bounds.distance(point)) {
+                value
+            } else {
+                brush.apply(value, point)
+            }
+        })
+    }
+
+    /// Adds `brush` on top of the others. The field changes within
+    /// `brush.bounds()`.
+    pub fn push(&self, brush: Brush) -> BrushId {
+        let id = BrushId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.brushes.write().unwrap().push((id, brush, brush.bounds()));
+        id
+    }
+
+    /// Removes the brush `id`, returning it unless it was already gone.
+    pub fn remove(&self, id: BrushId) -> Option<Brush> {
+        let mut brushes = self.brushes.write().unwrap();
+        let index = brushes.binary_search_by_key(&id, |&(id, _, _)| id).ok()?;
+        Some(brushes.remove(index).1)
+    }
+
+    /// Puts back a brush removed earlier, where its id places it among the
+    /// others. Does nothing if a brush with `id` is already there.
+    pub fn restore(&self, id: BrushId, brush: Brush) {
+        let mut brushes = self.brushes.write().unwrap();
+        if let Err(index) = brushes.binary_search_by_key(&id, |&(id, _, _)| id) {
+            brushes.insert(index, (id, brush, brush.bounds()));
+        }
+    }
+
+    /// Removes the brush added last.
+    pub fn pop(&self) -> Option<Brush> {
+        self.brushes.write().unwrap().pop().map(|(_, brush, _)| brush)
+    }
+
+    pub fn brushes(&self) -> Vec<Brush> {
+        self.brushes.read().unwrap().iter().map(|&(_, brush, _)| brush).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.brushes.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<B: MeshBackend> Octree<B> {
+    /// Adds `brush` to `layer`, which this octree's worker must be sampling
+    /// through (see `EditLayer::over`), and regenerates the nodes it reaches.
+    /// Returns the id to remove the brush with.
+    pub fn edit(&mut self, layer: &EditLayer, brush: Brush) -> BrushId {
+        let id = layer.push(brush);
+        self.invalidate(&brush.bounds());
+        id
+    }
+
+    /// Regenerates every node whose mesh depends on the field inside
+    /// `bounds`. Returns how many were requeued.
+    pub fn invalidate(&mut self, bounds: &Bounds) -> usize {
+        let mut count = 0;
+        self.visit(Order::PreOrder, |node, info, view| {
+            // Children lie inside their parent, so a miss prunes the subtree
+            if !bounds.intersects_node(view.key, isosurface::MARGIN) {
+                return Visit::SkipChildren;
+            }
+            node.regenerate(info, view.key);
+            count += 1;
+            Visit::Continue
+        });
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RecordingBackend;
+    use crate::worker::Worker;
+
+    fn sphere(x: f64, y: f64, z: f64) -> f64 {
+        (x.powi(2) + y.powi(2) + z.powi(2)).sqrt() - 0.4
+    }
+
+    #[test]
+    fn brushes() {
+        let hole = Brush { shape: Shape::Sphere { center: Vector3::new(0.4, 0.0, 0.0), radius: 0.1 }, operation: Operation::Subtract };
+        assert!(hole.apply(sphere(0.35, 0.0, 0.0), Vector3::new(0.35, 0.0, 0.0)) > 0.0);
+        assert!(hole.apply(sphere(0.25, 0.0, 0.0), Vector3::new(0.25, 0.0, 0.0)) < 0.0);
+
+        let block = Shape::Box { center: Vector3::new(0.0, 0.0, 0.0), half_extents: Vector3::new(0.1, 0.2, 0.3) };
+        assert!((block.distance(Vector3::new(0.0, 0.5, 0.0)) - 0.3).abs() < 1e-12);
+        assert!((block.distance(Vector3::new(0.05, 0.0, 0.0)) + 0.05).abs() < 1e-12);
+
+        // Smooth blends only differ from hard ones near the seam
+        let mound = Brush { shape: Shape::Sphere { center: Vector3::new(0.0, 0.5, 0.0), radius: 0.15 }, operation: Operation::SmoothAdd { blend: 0.05 } };
+        let far = Vector3::new(0.0, -0.2, 0.0);
+        assert!(mound.apply(sphere(far.x, far.y, far.z), far) == sphere(far.x, far.y, far.z));
+        let seam = Vector3::new(0.0, 0.375, 0.0);
+        assert!(mound.apply(sphere(seam.x, seam.y, seam.z), seam) < sphere(seam.x, seam.y, seam.z).min(-0.025));
+        assert!((mound.bounds().max.y - 0.7).abs() < 1e-12);
+
+        // The field stays continuous across a brush's bounds
+        let layer = EditLayer::new();
+        layer.push(Brush { shape: Shape::Sphere { center: Vector3::new(0.5, 0.0, 0.0), radius: 0.1 }, operation: Operation::Add });
+        let inside = layer.sample(&sphere, 0.6 - 1e-9, 0.0, 0.0);
+        let outside = layer.sample(&sphere, 0.6 + 1e-9, 0.0, 0.0);
+        assert!(inside.abs() < 1e-6 && outside.abs() < 1e-6);
+        assert!(layer.sample(&sphere, -0.5, 0.0, 0.0) == sphere(-0.5, 0.0, 0.0));
+    }
+
+    #[test]
+    fn requeues_touched_nodes() {
+        let layer = EditLayer::new();
+        let mut octree = Octree::with_worker(RecordingBackend::new(), Worker::synchronous(layer.over(sphere)));
+        octree.walk(&|node, info, key, level, x, y, z| {
+            if level < 2 {
+                node.create_children(info, key, level, x, y, z);
+            }
+        });
+        octree.walk(&|node, info, key, level, x, y, z| {
+            if level < 2 {
+                node.create_children(info, key, level, x, y, z);
+            }
+        });
+        octree.update();
+        assert!(octree.backend().uploads() == 73);
+
+        // A small hole near +x only reaches the root, one level-1 node and a
+        // few of its children
+        let hole = Brush { shape: Shape::Sphere { center: Vector3::new(0.4, 0.1, 0.1), radius: 0.05 }, operation: Operation::Subtract };
+        octree.edit(&layer, hole);
+        let requeued = octree.backend().uploads();
+        octree.update();
+        let requeued = octree.backend().uploads() - requeued;
+        assert!(requeued > 2 && requeued < 10);
+        assert!(octree.backend().resident() == 73);
+        assert!(octree.cancel_stats().stale == 0);
+
+        let hit = octree.raycast(Vector3::new(2.0, 0.1, 0.1), Vector3::new(-1.0, 0.0, 0.0), 10.0).unwrap();
+        assert!(hit.point.x < 0.36);
+        assert!(layer.len() == 1);
+    }
+}
@@ -1,6 +1,7 @@
 use std;
 use quick_error::*;
 use sdl2;
+use crate::octree::NodeKey;
 
 quick_error!{
     #[derive(Debug)]
@@ -26,6 +27,43 @@ quick_error!{
             description(log)
         }
 
+        Archive(message: String) {
+            description(message)
+            display("Invalid archive: {}", message)
+        }
+
+        Panic(key: NodeKey, message: String) {
+            description(message)
+            display("Generating chunk {:?} panicked: {}", key, message)
+        }
+
+        Cancelled(key: NodeKey) {
+            description("job cancelled")
+            display("Job for chunk {:?} was cancelled", key)
+        }
+
+        WorkerStopped {
+            description("worker stopped before the job finished")
+        }
+
+        OutputTaken {
+            description("job output was already taken")
+        }
+
+        QueueFull {
+            description("synchronous worker is full: receive results before sending more tasks")
+        }
+
+        Remote(message: String) {
+            description(message)
+            display("Remote worker: {}", message)
+        }
+
+        Usage(message: String) {
+            description(message)
+            display("{}", message)
+        }
+
         SDL(err: sdl2::Error) {
             from()
             cause(err)
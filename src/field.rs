@@ -0,0 +1,26 @@
+use crate::material::Material;
+
+/// Procedural planet: a sphere of radius ~0.5 with layered cosine noise on
+/// its surface. Negative values are inside the planet.
+pub fn planet(x: f64, y: f64, z: f64) -> f64 {
+    ((((z * 3.0).cos() + x+y) * ((y*6.0).cos() + 1.1) * 300.0).cos() * 0.0003 + (((x * 3.0).cos() + y+z) * ((z*6.0).cos() + 1.1) * 250.0).cos() * 0.001 + (((y * 3.0).cos() + z+x) * ((x*6.0).cos() + 1.1) * 200.0).cos() * 0.0004).abs() + (x*300.0).cos() * 0.0001 + x.powi(2) + y.powi(2) + z.powi(2)  - 0.248
+}
+
+/// `planet` with its materials: ice around the poles and snow next to it and
+/// on high ground, sand in low-lying areas and rock elsewhere.
+pub fn planet_with_materials(x: f64, y: f64, z: f64) -> (f64, Material) {
+    let r = (x.powi(2) + y.powi(2) + z.powi(2)).sqrt();
+    let latitude = if r > 0.0 { y.abs() / r } else { 0.0 };
+    // The noise only ever lowers the surface, from a radius of about 0.498
+    // down to about 0.4962
+    let material = if latitude > 0.92 {
+        Material::Ice
+    } else if latitude > 0.8 || r > 0.4976 {
+        Material::Snow
+    } else if r < 0.4967 {
+        Material::Sand
+    } else {
+        Material::Rock
+    };
+    (planet(x, y, z), material)
+}
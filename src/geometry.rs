@@ -2,6 +2,7 @@ use std;
 use gl;
 use gl::types::*;
 
+use crate::material;
 use crate::shader::Attribute;
 
 pub mod traits {
@@ -9,6 +10,15 @@ pub mod traits {
         fn position_offset() -> Option<usize>;
         fn normal_offset() -> Option<usize>;
         fn uv_offset() -> Option<usize>;
+        /// Offset of the material blend weights, one `GLfloat` per material.
+        fn material_offset() -> Option<usize> {
+            None
+        }
+        /// Offset of the tangent, four `GLfloat`s: its direction, then the
+        /// sign of the bitangent.
+        fn tangent_offset() -> Option<usize> {
+            None
+        }
     }
 }
 
@@ -18,6 +28,11 @@ pub struct Vertex {
     pub position: [GLfloat; 3],
     pub normal: [GLfloat; 3],
     pub uv: [GLfloat; 2],
+    /// Blend weights of each `Material`, by index, summing to one.
+    pub material: [GLfloat; material::COUNT],
+    /// Direction of increasing `uv[0]` along the surface, and in `w` the sign
+    /// of the bitangent, `normal × tangent`, along increasing `uv[1]`.
+    pub tangent: [GLfloat; 4],
 }
 
 impl traits::Vertex for Vertex {
@@ -33,6 +48,14 @@ impl traits::Vertex for Vertex {
     fn uv_offset() -> Option<usize> {
         Some(std::mem::align_of::<GLfloat>() * 6)
     }
+    #[inline]
+    fn material_offset() -> Option<usize> {
+        Some(std::mem::align_of::<GLfloat>() * 8)
+    }
+    #[inline]
+    fn tangent_offset() -> Option<usize> {
+        Some(std::mem::align_of::<GLfloat>() * (8 + material::COUNT))
+    }
 }
 
 pub struct Geometry {
@@ -93,6 +116,28 @@ impl Geometry {
                                                 uv_offset as GLuint);
                     gl::VertexArrayAttribBinding(vao, Attribute::UV as GLuint, 0);
                 }
+
+                if let Some(material_offset) = V::material_offset() {
+                    gl::EnableVertexArrayAttrib(vao, Attribute::Material as GLuint);
+                    gl::VertexArrayAttribFormat(vao,
+                                                Attribute::Material as GLuint,
+                                                material::COUNT as GLint,
+                                                gl::FLOAT,
+                                                gl::FALSE,
+                                                material_offset as GLuint);
+                    gl::VertexArrayAttribBinding(vao, Attribute::Material as GLuint, 0);
+                }
+
+                if let Some(tangent_offset) = V::tangent_offset() {
+                    gl::EnableVertexArrayAttrib(vao, Attribute::Tangent as GLuint);
+                    gl::VertexArrayAttribFormat(vao,
+                                                Attribute::Tangent as GLuint,
+                                                4,
+                                                gl::FLOAT,
+                                                gl::FALSE,
+                                                tangent_offset as GLuint);
+                    gl::VertexArrayAttribBinding(vao, Attribute::Tangent as GLuint, 0);
+                }
             }
         }
 
@@ -0,0 +1,216 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use crate::backend::MeshBackend;
+use crate::delta::{DeltaStore, SampleChange};
+use crate::edit::{Bounds, Brush, BrushId, EditLayer};
+use crate::octree::Octree;
+
+enum Change {
+    Brush { layer: Arc<EditLayer>, id: BrushId, brush: Brush },
+    Delta { store: Arc<DeltaStore>, samples: Vec<SampleChange> },
+}
+
+/// A change along with the region of the field it affects.
+struct Entry {
+    change: Change,
+    bounds: Bounds,
+}
+
+/// Undo and redo for terrain edits. Edits made through the history can be
+/// stepped back and forth, regenerating only the nodes each one reaches.
+///
+/// Brushes are undone by removing them from their layer by id, and deltas by
+/// taking away the difference they made, so edits made to the same layer or
+/// store elsewhere stay in place.
+pub struct History {
+    undo: VecDeque<Entry>,
+    redo: Vec<Entry>,
+    /// Most edits kept; the oldest are forgotten beyond this.
+    limit: usize,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::with_limit(usize::MAX)
+    }
+
+    pub fn with_limit(limit: usize) -> History {
+        History { undo: VecDeque::new(), redo: Vec::new(), limit }
+    }
+
+    /// Adds `brush` to `layer` like `Octree::edit`, recording it.
+    pub fn edit<B: MeshBackend>(&mut self, octree: &mut Octree<B>, layer: &Arc<EditLayer>, brush: Brush) {
+        let id = layer.push(brush);
+        let bounds = brush.bounds();
+        octree.invalidate(&bounds);
+        self.record(Entry { change: Change::Brush { layer: layer.clone(), id, brush }, bounds });
+    }
+
+    /// Adds `delta` to `store` like `Octree::modify`, recording the samples
+    /// it changes.
+    pub fn modify<B: MeshBackend>(&mut self, octree: &mut Octree<B>, store: &Arc<DeltaStore>, bounds: &Bounds, delta: impl Fn(f64, f64, f64) -> f64) {
+        let (bounds, samples) = store.modify_recorded(bounds, delta);
+        octree.invalidate(&bounds);
+        if !samples.is_empty() {
+            self.record(Entry { change: Change::Delta { store: store.clone(), samples }, bounds });
+        }
+    }
+
+    fn record(&mut self, entry: Entry) {
+        self.redo.clear();
+        self.undo.push_back(entry);
+        if self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+    }
+
+    /// Reverts the latest edit. Returns false if there was nothing to undo.
+    /// Brushes removed from their layer some other way in the meantime are
+    /// skipped and forgotten.
+    pub fn undo<B: MeshBackend>(&mut self, octree: &mut Octree<B>) -> bool {
+        while let Some(entry) = self.undo.pop_back() {
+            let changed = match entry.change {
+                Change::Brush { ref layer, id, .. } => layer.remove(id).is_some(),
+                Change::Delta { ref store, ref samples } => {
+                    store.revert(samples, true);
+                    true
+                }
+            };
+            if changed {
+                octree.invalidate(&entry.bounds);
+                self.redo.push(entry);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Reapplies the latest undone edit. Returns false if there was nothing to
+    /// redo.
+    pub fn redo<B: MeshBackend>(&mut self, octree: &mut Octree<B>) -> bool {
+        let entry = match self.redo.pop() {
+            Some(entry) => entry,
+            None => return false,
+        };
+        match entry.change {
+            Change::Brush { ref layer, id, brush } => layer.restore(id, brush),
+            Change::Delta { ref store, ref samples } => store.revert(samples, false),
+        }
+        octree.invalidate(&entry.bounds);
+        self.undo.push_back(entry);
+        true
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Vector3;
+    use crate::backend::RecordingBackend;
+    use crate::edit::{Operation, Shape};
+    use crate::worker::Worker;
+
+    #[test]
+    fn undo_redo() {
+        let layer = EditLayer::new();
+        let store = DeltaStore::new(3);
+        let field = layer.over(store.over(|x, y, z| (x * x + y * y + z * z).sqrt() - 0.4));
+        let mut octree = Octree::with_worker(RecordingBackend::new(), Worker::synchronous(field));
+        octree.walk(&|node, info, key, level, x, y, z| {
+            node.create_children(info, key, level, x, y, z);
+        });
+        octree.update();
+        let depth = |octree: &Octree<RecordingBackend>| {
+            octree.raycast(Vector3::new(2.0, 0.0, 0.0), Vector3::new(-1.0, 0.0, 0.0), 10.0).unwrap().point.x
+        };
+        assert!((depth(&octree) - 0.4).abs() < 1e-6);
+
+        let mut history = History::with_limit(2);
+        let hole = Brush { shape: Shape::Sphere { center: Vector3::new(0.4, 0.0, 0.0), radius: 0.1 }, operation: Operation::Subtract };
+        history.edit(&mut octree, &layer, hole);
+        let region = Bounds { min: Vector3::new(0.2, -0.05, -0.05), max: Vector3::new(0.35, 0.05, 0.05) };
+        history.modify(&mut octree, &store, &region, |_, _, _| 0.5);
+        octree.update();
+        assert!(depth(&octree) < 0.21);
+
+        // Each step regenerates the root and the children on the +x side
+        let uploads = octree.backend().uploads();
+        assert!(history.undo(&mut octree));
+        octree.update();
+        assert!(octree.backend().uploads() - uploads == 5);
+        assert!((depth(&octree) - 0.3).abs() < 1e-6);
+        assert!(history.undo(&mut octree));
+        assert!(!history.undo(&mut octree));
+        assert!(layer.is_empty());
+        assert!(store.sample(0.3, 0.0, 0.0) == 0.0);
+        octree.update();
+        assert!((depth(&octree) - 0.4).abs() < 1e-6);
+
+        assert!(history.redo(&mut octree));
+        octree.update();
+        assert!((depth(&octree) - 0.3).abs() < 1e-6);
+
+        // A new edit drops what could have been redone, and the limit drops
+        // the oldest edit
+        history.modify(&mut octree, &store, &region, |_, _, _| 0.1);
+        assert!(!history.can_redo());
+        history.modify(&mut octree, &store, &region, |_, _, _| 0.1);
+        assert!(history.undo(&mut octree) && history.undo(&mut octree));
+        assert!(!history.undo(&mut octree));
+        assert!(layer.len() == 1);
+
+        // Undoing a brush leaves the ones added around it alone
+        history.clear();
+        let bump = Brush { shape: Shape::Sphere { center: Vector3::new(-0.4, 0.0, 0.0), radius: 0.1 }, operation: Operation::Add };
+        let other = layer.push(bump);
+        history.edit(&mut octree, &layer, bump);
+        layer.push(hole);
+        assert!(history.undo(&mut octree));
+        assert!(layer.brushes() == vec![hole, bump, hole]);
+        assert!(history.redo(&mut octree));
+        assert!(layer.brushes() == vec![hole, bump, bump, hole]);
+        assert!(layer.remove(other) == Some(bump));
+
+        // A brush removed some other way isn't undone, so nothing changes
+        history.clear();
+        history.edit(&mut octree, &layer, bump);
+        assert!(layer.pop() == Some(bump));
+        octree.update();
+        let uploads = octree.backend().uploads();
+        assert!(!history.undo(&mut octree));
+        octree.update();
+        assert!(octree.backend().uploads() == uploads);
+        assert!(layer.brushes() == vec![hole, bump, hole]);
+
+        // Undoing a delta leaves changes made to the store directly alone
+        history.clear();
+        let before = store.sample(0.3, 0.0, 0.0);
+        history.modify(&mut octree, &store, &region, |_, _, _| 0.5);
+        store.modify(&region, |_, _, _| 0.25);
+        assert!(history.undo(&mut octree));
+        assert!((store.sample(0.3, 0.0, 0.0) - (before + 0.25)).abs() < 1e-6);
+        assert!(history.redo(&mut octree));
+        assert!((store.sample(0.3, 0.0, 0.0) - (before + 0.75)).abs() < 1e-6);
+    }
+}
@@ -1,4 +1,6 @@
+use gl::types::*;
 use crate::geometry::{Geometry, Vertex};
+use crate::material::{self, Material};
 
 pub trait Isosurface {
     fn isosurface<'a>(field: &(Fn(f64, f64, f64) -> f64 + 'a)) -> Self;
@@ -10,12 +12,27 @@ const HALF: f64 = STEP / 2.0;
 const ____: f64 = 0.0;
 const OVER: i32 = 3;
 
+/// How far, in node sizes, beyond a node's bounds its mesh samples the field.
+/// A change to the field further out than this leaves the mesh unchanged.
+pub const MARGIN: f64 = (OVER + 2) as f64 * STEP;
+
+/// Most vertices `isosurface` can produce: six faces of two triangles for
+/// every cell it visits.
+pub const MAX_VERTICES: usize = ((COUNT + 2 * OVER) * (COUNT + 2 * OVER) * (COUNT + 2 * OVER) * 36) as usize;
+
+/// World-space edge length covered by one repeat of a texture.
+pub const TEXTURE_SIZE: f64 = 1.0 / 256.0;
+
 impl Isosurface for Geometry {
     fn isosurface<'a>(field: &(Fn(f64, f64, f64) -> f64 + 'a)) -> Geometry {
-        Geometry::from(Vec::<Vertex>::isosurface(field).as_ref())
+        let mut data = Vec::<Vertex>::isosurface(field);
+        project_textures(&mut data, [0.0, 0.0, 0.0], 1.0);
+        Geometry::from(data.as_ref())
     }
 }
 
+/// Leaves texture coordinates and tangents zeroed; they depend on where the
+/// mesh ends up, see `project_textures`.
 impl Isosurface for Vec<Vertex> {
     fn isosurface<'a>(field: &(Fn(f64, f64, f64) -> f64 + 'a)) -> Vec<Vertex> {
         let mut data = Vec::<Vertex>::with_capacity(50000);
@@ -52,6 +69,118 @@ impl Isosurface for Vec<Vertex> {
     }
 }
 
+/// Cell centres `isosurface` tests along each axis, including the ring of
+/// neighbours it looks at beyond the outermost cells.
+const SIDE: usize = (COUNT + 2 * OVER + 2) as usize;
+
+/// Like `isosurface`, for a field returning density and material together.
+/// Every cell centre is sampled once, and each vertex blends the materials
+/// of the eight centres around it, so vertices near a boundary between
+/// materials blend them.
+pub fn with_materials<'a>(field: &(dyn Fn(f64, f64, f64) -> (f64, Material) + 'a)) -> Vec<Vertex> {
+    let centre = |i: usize| f64::from(i as i32 - OVER - 1 - COUNT / 2) * STEP + HALF;
+    let mut samples = Vec::with_capacity(SIDE * SIDE * SIDE);
+    for x in 0..SIDE {
+        for y in 0..SIDE {
+            for z in 0..SIDE {
+                samples.push(field(centre(x), centre(y), centre(z)));
+            }
+        }
+    }
+
+    // Index of the centre at or below `c`
+    let index = |c: f64| ((c - HALF) / STEP).floor() + f64::from(OVER + 1 + COUNT / 2);
+    let sample = |x: f64, y: f64, z: f64| {
+        let (x, y, z) = (index(x), index(y), index(z));
+        let inside = |i: f64| i >= 0.0 && i < SIDE as f64;
+        if inside(x) && inside(y) && inside(z) {
+            Some(&samples[(x as usize * SIDE + y as usize) * SIDE + z as usize])
+        } else {
+            None
+        }
+    };
+
+    let mut data = Vec::<Vertex>::isosurface(&|x, y, z| match sample(x, y, z) {
+        // Vertex placement samples between the centres too
+        Some(&(density, _)) if [x, y, z].iter().all(|&c| centre(index(c) as usize) == c) => density,
+        _ => field(x, y, z).0,
+    });
+    for vertex in data.iter_mut() {
+        let [x, y, z] = vertex.position;
+        let (x, y, z) = (f64::from(x), f64::from(y), f64::from(z));
+        let corners = (0..8).filter_map(|corner| sample(
+            x + if corner & 4 == 0 { 0.0 } else { STEP },
+            y + if corner & 2 == 0 { 0.0 } else { STEP },
+            z + if corner & 1 == 0 { 0.0 } else { STEP },
+        ));
+        vertex.material = material::weights(corners.map(|&(_, material)| material));
+    }
+    data
+}
+
+/// Texture coordinates and tangent for the point `offset` away from `origin`
+/// on the surface with the given normal. As in triplanar mapping, the point
+/// is projected onto the plane of the axis the normal is closest to, and the
+/// tangent follows `u` across that plane.
+///
+/// The origin's share is wrapped to a single texture repeat before it's
+/// added, so coordinates stay small enough for `f32` however far out the
+/// mesh is; they still line up across meshes up to whole repeats.
+pub fn texture_frame(origin: [f64; 3], offset: [f64; 3], normal: [f64; 3]) -> ([GLfloat; 2], [GLfloat; 4]) {
+    let [n_x, n_y, n_z] = [normal[0].abs(), normal[1].abs(), normal[2].abs()];
+    let (u, v) = if n_x >= n_y && n_x >= n_z {
+        ([0.0, 0.0, 1.0], [0.0, 1.0, 0.0])
+    } else if n_y >= n_z {
+        ([1.0, 0.0, 0.0], [0.0, 0.0, 1.0])
+    } else {
+        ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0])
+    };
+    let uv = [
+        ((dot(origin, u) / TEXTURE_SIZE).rem_euclid(1.0) + dot(offset, u) / TEXTURE_SIZE) as f32,
+        ((dot(origin, v) / TEXTURE_SIZE).rem_euclid(1.0) + dot(offset, v) / TEXTURE_SIZE) as f32,
+    ];
+    (uv, tangent(normal, u, v))
+}
+
+/// Tangent along `u`, made orthogonal to `normal`, with the sign telling
+/// whether the bitangent points along `v`. `u` must not be parallel to the
+/// normal.
+fn tangent(normal: [f64; 3], u: [f64; 3], v: [f64; 3]) -> [GLfloat; 4] {
+    let d = dot(normal, u);
+    let t = [u[0] - normal[0] * d, u[1] - normal[1] * d, u[2] - normal[2] * d];
+    let l = dot(t, t).sqrt();
+    let t = [t[0] / l, t[1] / l, t[2] / l];
+    let b = [
+        normal[1] * t[2] - normal[2] * t[1],
+        normal[2] * t[0] - normal[0] * t[2],
+        normal[0] * t[1] - normal[1] * t[0],
+    ];
+    let w = if dot(b, v) < 0.0 { -1.0 } else { 1.0 };
+    [t[0] as f32, t[1] as f32, t[2] as f32, w]
+}
+
+/// Sets the texture coordinates and tangents of `data`, generated by
+/// `isosurface` for a node centred on `center` with edge length `size`, so
+/// they are projected in world space and line up across nodes and levels.
+pub fn project_textures(data: &mut [Vertex], center: [f64; 3], size: f64) {
+    for vertex in data.iter_mut() {
+        let offset = [
+            f64::from(vertex.position[0]) * size,
+            f64::from(vertex.position[1]) * size,
+            f64::from(vertex.position[2]) * size,
+        ];
+        let normal = [f64::from(vertex.normal[0]), f64::from(vertex.normal[1]), f64::from(vertex.normal[2])];
+        let (uv, tangent) = texture_frame(center, offset, normal);
+        vertex.uv = uv;
+        vertex.tangent = tangent;
+    }
+}
+
+#[inline]
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
 #[inline]
 fn test<'a>(field: &(Fn(f64, f64, f64) -> f64 + 'a), x: f64, y: f64, z: f64) -> bool {
     field(x + HALF, y + HALF, z + HALF) < 0.0
@@ -88,11 +217,12 @@ fn vertex<'a>(field: &(Fn(f64, f64, f64) -> f64 + 'a), x: f64, y: f64, z: f64) -
     let n_z = field(x + ____, y + ____, z + STEP) - field(x + ____, y + ____, z - STEP);
 
     let l = (n_x.powi(2) + n_y.powi(2) + n_z.powi(2)).sqrt();
-
     Vertex {
         position: [x as f32, y as f32, z as f32],
         normal: [(n_x / l) as f32, (n_y / l) as f32, (n_z / l) as f32],
         uv: [0.0, 0.0],
+        material: Material::default().weights(),
+        tangent: [0.0, 0.0, 0.0, 0.0],
     }
 }
 
@@ -166,3 +296,68 @@ fn bottom<'a>(field: &(Fn(f64, f64, f64) -> f64 + 'a), x: f64, y: f64, z: f64) -
      vertex(field, x + ____, y + ____, z + ____),
      vertex(field, x + STEP, y + ____, z + ____)]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn texture_coordinates() {
+        // A steep slope facing mostly +x is projected onto the zy plane
+        let normal = [0.8, 0.6, 0.0];
+        let (uv, tangent) = texture_frame([0.0, 0.0, 0.0], [0.5, 0.25, -0.125], normal);
+        assert!(uv == [-32.0, 64.0]);
+        assert!(tangent == [0.0, 0.0, 1.0, -1.0]);
+
+        // Far from the origin, nearby points still get distinct coordinates
+        let origin = [1234.5678, 0.0, 8765.4321];
+        let (a, _) = texture_frame(origin, [0.0, 0.0, 0.0], normal);
+        let (b, _) = texture_frame(origin, [0.0, 0.0, 1e-6], normal);
+        assert!(((b[0] - a[0]) - 2.56e-4).abs() < 1e-6 && a[0].abs() <= 1.0);
+
+        let mut data = Vec::<Vertex>::isosurface(&|x, y, z| (x * x + y * y + z * z).sqrt() - 0.3);
+        assert!(!data.is_empty());
+        project_textures(&mut data, [0.0, 0.0, 0.0], 1.0);
+        for vertex in &data {
+            let t = vertex.tangent;
+            let n = vertex.normal;
+            assert!((t[0] * t[0] + t[1] * t[1] + t[2] * t[2] - 1.0).abs() < 1e-4);
+            assert!((t[0] * n[0] + t[1] * n[1] + t[2] * n[2]).abs() < 1e-4);
+            assert!(t[3].abs() == 1.0);
+        }
+
+        // Projected in world space, the same point gets the same coordinates,
+        // up to whole repeats, whichever node it was meshed in
+        let mut vertex = data[0].clone();
+        let world = vertex.position;
+        let uv = vertex.uv;
+        vertex.position = [(world[0] - 0.3) * 2.0, world[1] * 2.0, world[2] * 2.0];
+        project_textures(std::slice::from_mut(&mut vertex), [0.3, 0.0, 0.0], 0.5);
+        for i in 0..2 {
+            let d = f64::from(vertex.uv[i] - uv[i]);
+            assert!((d - d.round()).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn materials_from_centres() {
+        let centres = Cell::new(0);
+        let data = with_materials(&|x, y, z| {
+            if [x, y, z].iter().all(|&c| ((c - HALF) / STEP).fract() == 0.0) {
+                centres.set(centres.get() + 1);
+            }
+            (x - 0.01, if y < 0.0 { Material::Sand } else { Material::Snow })
+        });
+        assert!(centres.get() == SIDE * SIDE * SIDE);
+
+        assert!(!data.is_empty());
+        for vertex in &data {
+            assert!((vertex.material.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+            if vertex.position[1] < -0.1 {
+                assert!(vertex.material == Material::Sand.weights());
+            }
+        }
+        assert!(data.iter().any(|vertex| vertex.material[Material::Sand.index()] > 0.0 && vertex.material[Material::Snow.index()] > 0.0));
+    }
+}
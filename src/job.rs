@@ -0,0 +1,340 @@
+#![allow(dead_code)]
+
+use std::panic::{self, AssertUnwindSafe};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Waker};
+use crate::error::Error;
+use crate::geometry::Vertex;
+use crate::isosurface::{self, Isosurface};
+use crate::material::Material;
+use crate::octree::NodeKey;
+
+pub type ScalarField = dyn Fn(f64, f64, f64) -> f64 + Send + Sync;
+
+/// Per-chunk work for the worker pool. Mesh generation is one job; others
+/// (collision meshes, scattering, statistics...) are sent with
+/// `Worker::submit` and get their output back through a `JobHandle`.
+pub trait Job: Send + 'static {
+    type Output: Send + 'static;
+
+    fn run(&self, context: &JobContext) -> Self::Output;
+}
+
+/// The node a job runs for, and the field it samples.
+pub struct JobContext<'a> {
+    pub key: NodeKey,
+    pub level: i32,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    /// Edge length of the node.
+    pub size: f64,
+    scalar_field: &'a (dyn Fn(f64, f64, f64) -> f64 + Send + Sync + 'a),
+    material_field: Option<&'a (dyn Fn(f64, f64, f64) -> (f64, Material) + Send + Sync + 'a)>,
+}
+
+impl<'a> JobContext<'a> {
+    pub fn new(key: NodeKey, scalar_field: &'a (dyn Fn(f64, f64, f64) -> f64 + Send + Sync + 'a)) -> JobContext<'a> {
+        let (x, y, z) = key.center();
+        JobContext { key, level: key.level, x, y, z, size: key.size(), scalar_field, material_field: None }
+    }
+
+    /// Lets the job look up materials in `material_field`, which returns
+    /// the density along with them.
+    pub fn with_materials(self, material_field: Option<&'a (dyn Fn(f64, f64, f64) -> (f64, Material) + Send + Sync + 'a)>) -> JobContext<'a> {
+        JobContext { material_field, ..self }
+    }
+
+    /// Whether the field has materials; without them every point is
+    /// `Material::default()`.
+    pub fn has_materials(&self) -> bool {
+        self.material_field.is_some()
+    }
+
+    /// The material in world coordinates.
+    #[inline]
+    pub fn material(&self, x: f64, y: f64, z: f64) -> Material {
+        self.material_field.map_or_else(Material::default, |field| field(x, y, z).1)
+    }
+
+    /// The density and material in world coordinates, from a single
+    /// evaluation when the field has materials.
+    #[inline]
+    pub fn sample_with_material(&self, x: f64, y: f64, z: f64) -> (f64, Material) {
+        match self.material_field {
+            Some(field) => field(x, y, z),
+            None => (self.sample(x, y, z), Material::default()),
+        }
+    }
+
+    /// `sample_with_material` in node-local coordinates, like
+    /// `sample_local`.
+    #[inline]
+    pub fn sample_with_material_local(&self, x: f64, y: f64, z: f64) -> (f64, Material) {
+        self.sample_with_material(self.x + x * self.size, self.y + y * self.size, self.z + z * self.size)
+    }
+
+    /// Samples the field in world coordinates.
+    #[inline]
+    pub fn sample(&self, x: f64, y: f64, z: f64) -> f64 {
+        (self.scalar_field)(x, y, z)
+    }
+
+    /// Samples the field in node-local coordinates, where the node spans
+    /// [-0.5, 0.5] on each axis (the space meshes are generated in). Offsets
+    /// are scaled before being added to the centre, so deep nodes are
+    /// sampled as precisely as `f64` allows.
+    #[inline]
+    pub fn sample_local(&self, x: f64, y: f64, z: f64) -> f64 {
+        (self.scalar_field)(
+            self.x + x * self.size,
+            self.y + y * self.size,
+            self.z + z * self.size,
+        )
+    }
+}
+
+/// Generates the node's isosurface mesh.
+pub struct MeshJob;
+
+impl Job for MeshJob {
+    type Output = Vec<Vertex>;
+
+    fn run(&self, context: &JobContext) -> Vec<Vertex> {
+        let mut data = if context.has_materials() {
+            isosurface::with_materials(&|x, y, z| context.sample_with_material_local(x, y, z))
+        } else {
+            Vec::<Vertex>::isosurface(&|x, y, z| context.sample_local(x, y, z))
+        };
+        isosurface::project_textures(&mut data, [context.x, context.y, context.z], context.size);
+        data
+    }
+}
+
+/// Samples the field on a regular grid over the node.
+pub struct FieldStatsJob {
+    /// Samples per axis.
+    pub resolution: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FieldStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// Fraction of samples inside the surface (negative).
+    pub inside: f64,
+}
+
+impl Job for FieldStatsJob {
+    type Output = FieldStats;
+
+    fn run(&self, context: &JobContext) -> FieldStats {
+        let resolution = self.resolution.max(1);
+        let step = 1.0 / resolution as f64;
+        let mut stats = FieldStats { min: f64::INFINITY, max: f64::NEG_INFINITY, mean: 0.0, inside: 0.0 };
+        for i in 0..resolution {
+            for j in 0..resolution {
+                for k in 0..resolution {
+                    let value = context.sample_local(
+                        (i as f64 + 0.5) * step - 0.5,
+                        (j as f64 + 0.5) * step - 0.5,
+                        (k as f64 + 0.5) * step - 0.5,
+                    );
+                    stats.min = stats.min.min(value);
+                    stats.max = stats.max.max(value);
+                    stats.mean += value;
+                    if value < 0.0 {
+                        stats.inside += 1.0;
+                    }
+                }
+            }
+        }
+        let count = resolution.pow(3) as f64;
+        stats.mean /= count;
+        stats.inside /= count;
+        stats
+    }
+}
+
+/// A job with its output type erased, as stored in the worker's queue.
+/// Either method delivers the outcome to the job's handle; `run` calls
+/// `panicked` first if the job panicked, so the panic is counted by the time
+/// the handle sees it.
+pub(crate) trait ErasedJob: Send {
+    fn run(self: Box<Self>, context: &JobContext, panicked: &dyn Fn());
+    fn fail(self: Box<Self>, error: Error);
+}
+
+struct Submitted<J: Job> {
+    job: J,
+    promise: Promise<J::Output>,
+}
+
+impl<J: Job> ErasedJob for Submitted<J> {
+    fn run(self: Box<Self>, context: &JobContext, panicked: &dyn Fn()) {
+        let output = if self.promise.is_cancelled() {
+            Err(Error::Cancelled(context.key))
+        } else {
+            run_caught(&self.job, context)
+        };
+        if let Err(Error::Panic(..)) = output {
+            panicked();
+        }
+        self.promise.resolve(output);
+    }
+
+    fn fail(self: Box<Self>, error: Error) {
+        self.promise.resolve(Err(error));
+    }
+}
+
+/// Wraps `job` for the worker's queue, returning the handle its output will
+/// be delivered to.
+pub(crate) fn erase<J: Job>(job: J) -> (Box<dyn ErasedJob>, JobHandle<J::Output>) {
+    let (promise, handle) = promise();
+    (Box::new(Submitted { job, promise }), handle)
+}
+
+/// Runs `job`, turning a panic into `Error::Panic`.
+pub(crate) fn run_caught<J: Job + ?Sized>(job: &J, context: &JobContext) -> Result<J::Output, Error> {
+    panic::catch_unwind(AssertUnwindSafe(|| job.run(context)))
+        .map_err(|payload| Error::Panic(context.key, panic_message(payload)))
+}
+
+struct Slot<T> {
+    state: Mutex<SlotState<T>>,
+    ready: Condvar,
+    cancelled: AtomicBool,
+}
+
+struct SlotState<T> {
+    output: Option<Result<T, Error>>,
+    /// Whether the handle has taken `output` already.
+    taken: bool,
+    waker: Option<Waker>,
+}
+
+impl<T> SlotState<T> {
+    fn take(&mut self) -> Option<Result<T, Error>> {
+        if self.taken {
+            return Some(Err(Error::OutputTaken));
+        }
+        let output = self.output.take();
+        self.taken = output.is_some();
+        output
+    }
+}
+
+/// Creates a connected promise and handle.
+pub(crate) fn promise<T>() -> (Promise<T>, JobHandle<T>) {
+    let slot = Arc::new(Slot {
+        state: Mutex::new(SlotState { output: None, taken: false, waker: None }),
+        ready: Condvar::new(),
+        cancelled: AtomicBool::new(false),
+    });
+    (Promise { slot: Some(slot.clone()) }, JobHandle { slot })
+}
+
+/// The worker's end of a `JobHandle`. Dropping it unresolved (e.g. when the
+/// worker shuts down with the task still queued) resolves the handle to
+/// `Error::WorkerStopped`.
+pub(crate) struct Promise<T> {
+    slot: Option<Arc<Slot<T>>>,
+}
+
+impl<T> Promise<T> {
+    pub fn is_cancelled(&self) -> bool {
+        self.slot.as_ref().is_some_and(|slot| slot.cancelled.load(Ordering::Relaxed))
+    }
+
+    pub fn resolve(mut self, output: Result<T, Error>) {
+        if let Some(slot) = self.slot.take() {
+            Promise::fill(&slot, output);
+        }
+    }
+
+    fn fill(slot: &Slot<T>, output: Result<T, Error>) {
+        let mut state = slot.state.lock().unwrap();
+        state.output = Some(output);
+        slot.ready.notify_all();
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Promise<T> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            Promise::fill(&slot, Err(Error::WorkerStopped));
+        }
+    }
+}
+
+/// Receives the output of a job sent with `Worker::submit`, or a mesh asked
+/// for with `Worker::request`.
+///
+/// Poll it with `try_get`, block on it with `wait`, or `.await` it: it is a
+/// `Future`, woken from the worker thread that finishes the job.
+pub struct JobHandle<T> {
+    slot: Arc<Slot<T>>,
+}
+
+/// Resolves to a chunk's mesh.
+pub type ChunkHandle = JobHandle<Vec<Vertex>>;
+
+impl<T> JobHandle<T> {
+    /// Returns the output if the job has finished. The output is only
+    /// returned once; after that, this, `wait` and `.await` return
+    /// `Error::OutputTaken`.
+    pub fn try_get(&self) -> Option<Result<T, Error>> {
+        self.slot.state.lock().unwrap().take()
+    }
+
+    /// Blocks until the job has finished. Synchronous workers only run jobs
+    /// in `pump`, so pump them before waiting.
+    pub fn wait(self) -> Result<T, Error> {
+        let mut state = self.slot.state.lock().unwrap();
+        loop {
+            if let Some(output) = state.take() {
+                return output;
+            }
+            state = self.slot.ready.wait(state).unwrap();
+        }
+    }
+
+    /// Skips the job if it hasn't started yet; it then resolves to
+    /// `Error::Cancelled`.
+    pub fn cancel(&self) {
+        self.slot.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<T> Future for JobHandle<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<T, Error>> {
+        let mut state = self.slot.state.lock().unwrap();
+        match state.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                state.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub(crate) fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        String::from(*message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
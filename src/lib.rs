@@ -0,0 +1,22 @@
+pub mod archive;
+pub mod backend;
+pub mod bake;
+pub mod cubesphere;
+pub mod delta;
+pub mod edit;
+pub mod error;
+pub mod field;
+pub mod shader;
+pub mod geometry;
+pub mod history;
+pub mod isosurface;
+pub mod job;
+pub mod material;
+pub mod metrics;
+pub mod octree;
+pub mod query;
+pub mod queue;
+#[cfg(unix)]
+pub mod remote;
+pub mod worker;
+pub mod reference_frame;
@@ -1,19 +1,15 @@
 use sdl2::event::Event;
 
-mod error;
-mod shader;
-mod geometry;
-mod isosurface;
-mod octree;
-mod worker;
-mod reference_frame;
-
-use crate::shader::{Shader, Uniform};
+use universe::{bake, field, queue};
+use universe::shader::{Shader, Uniform};
 use cgmath::prelude::*;
 use cgmath::{Vector3, Matrix4, Deg};
 use gl::types::*;
-use crate::octree::{Octree};
-use crate::reference_frame::ReferenceFrame;
+use std::time::Duration;
+use universe::backend::GlBackend;
+use universe::octree::{self, Octree, UploadBudget};
+use universe::worker::{Pool, Worker};
+use universe::reference_frame::ReferenceFrame;
 
 fn find_sdl_gl_driver() -> Option<u32> {
     for (index, item) in sdl2::render::drivers().enumerate() {
@@ -25,6 +21,30 @@ fn find_sdl_gl_driver() -> Option<u32> {
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("bake") {
+        if let Err(err) = bake::main(&args[2..]) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let remote = match args.get(1).map(String::as_str) {
+        Some("--remote") => match args.get(2) {
+            Some(socket) => Some(socket.as_str()),
+            None => {
+                eprintln!("Usage: universe --remote <socket>");
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+
+    view(remote);
+}
+
+fn view(remote: Option<&str>) {
     let sdl_context = sdl2::init().unwrap();
 
     let video_subsystem = sdl_context.video().unwrap();
@@ -59,8 +79,11 @@ fn main() {
 
     shader.select();
 
-    let scalar_field = |x: f64, y: f64, z: f64| ((((z * 3.0).cos() + x+y) * ((y*6.0).cos() + 1.1) * 300.0).cos() * 0.0003 + (((x * 3.0).cos() + y+z) * ((z*6.0).cos() + 1.1) * 250.0).cos() * 0.001 + (((y * 3.0).cos() + z+x) * ((x*6.0).cos() + 1.1) * 200.0).cos() * 0.0004).abs() + (x*300.0).cos() * 0.0001 + x.powi(2) + y.powi(2) + z.powi(2)  - 0.248;
-    let mut octree = Octree::new(scalar_field);
+    let mut octree = match remote {
+        Some(socket) => Octree::with_worker(GlBackend, Worker::remote(&[socket]).unwrap()),
+        None => Octree::with_worker(GlBackend, Pool::spawn().worker_with_materials(field::planet_with_materials)),
+    };
+    octree.set_upload_budget(UploadBudget { max_time: Some(Duration::from_millis(4)), ..Default::default() });
 
     let mut target_x: f64;
     let target_y: f64 = 0.0;
@@ -77,16 +100,18 @@ fn main() {
         target_x = (f64::from(t) / 57.2958).cos() * (0.625 - f64::from(t/10.0).cos() * 0.125);
         target_z = (f64::from(t) / 57.2958).sin() * (0.625 - f64::from(t/10.0).cos() * 0.125);
 
-        octree.walk(&|node, info, path, level, x, y, z| {
+        octree.set_priority(queue::by_distance(target_x, target_y, target_z));
+
+        octree.walk(&|node, info, key, level, x, y, z| {
             //println!("{{ level: {}, x: {}, y: {}, z: {} }}", level, x, y, z);
-            let inc = 0.5 / f64::from(1 << level);
+            let inc = 0.5 * octree::level_size(level);
             if level < 12 &&
                 target_x + 4.0 * inc >= x - inc && target_x - 4.0 * inc <= x + inc &&
                 target_y + 4.0 * inc >= y - inc && target_y - 4.0 * inc <= y + inc &&
                 target_z + 4.0 * inc >= z - inc && target_z - 4.0 * inc <= z + inc {
-                node.create_children(info, path, level, x, y, z);
+                node.create_children(info, key, level, x, y, z);
             } else {
-                node.destroy_children(info, path, level, x, y, z);
+                node.destroy_children(info, key, level, x, y, z);
             }
         });
 
@@ -115,16 +140,19 @@ fn main() {
 
         println!("{:?}", ReferenceFrame::transform(&r_planet, &r_ship).unwrap());
 
-        let model_view: Matrix4<GLfloat> = ReferenceFrame::transform(&r_planet, &r_ship).unwrap().cast();
+        let model_view: Matrix4<f64> = ReferenceFrame::transform(&r_planet, &r_ship).unwrap();
         octree.draw(model_view);
 
         r_planet.set(Matrix4::from_scale(0.01));
         r_ship.set(Matrix4::from_translation(Vector3::new(0.0, 0.005, 0.02)));
-        let model_view: Matrix4<GLfloat> = ReferenceFrame::transform(&r_planet, &r_ship).unwrap().cast();
+        let model_view: Matrix4<f64> = ReferenceFrame::transform(&r_planet, &r_ship).unwrap();
         octree.draw(model_view);
 
         canvas.present();
         octree.update();
+        for (_key, err) in octree.take_errors() {
+            eprintln!("{}", err);
+        }
 
         for event in events.poll_iter() {
             match event {
@@ -135,4 +163,6 @@ fn main() {
             }
         }
     }
+
+    print!("{}", octree.metrics());
 }
@@ -0,0 +1,98 @@
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use gl::types::*;
+use crate::job::ScalarField;
+
+/// What the ground is made of at a point of the field.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Material {
+    #[default]
+    Rock = 0,
+    Sand = 1,
+    Snow = 2,
+    Ice = 3,
+}
+
+/// Number of materials, and of blend weights in each vertex.
+pub const COUNT: usize = 4;
+
+impl Material {
+    pub const ALL: [Material; COUNT] = [Material::Rock, Material::Sand, Material::Snow, Material::Ice];
+
+    #[inline]
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn from_index(index: usize) -> Option<Material> {
+        Material::ALL.get(index).cloned()
+    }
+
+    /// Blend weights selecting only this material.
+    pub fn weights(self) -> [GLfloat; COUNT] {
+        let mut weights = [0.0; COUNT];
+        weights[self.index()] = 1.0;
+        weights
+    }
+}
+
+/// A field returning density and material together, so meshing gets both
+/// from one evaluation.
+pub type MaterialField = dyn Fn(f64, f64, f64) -> (f64, Material) + Send + Sync;
+
+/// Blend weights for the materials in `samples`, each counting equally.
+pub fn weights(samples: impl IntoIterator<Item = Material>) -> [GLfloat; COUNT] {
+    let mut weights = [0.0; COUNT];
+    let mut count = 0;
+    for material in samples {
+        weights[material.index()] += 1.0;
+        count += 1;
+    }
+    if count == 0 {
+        return Material::default().weights();
+    }
+    for weight in weights.iter_mut() {
+        *weight /= count as GLfloat;
+    }
+    weights
+}
+
+/// The material with the largest weight.
+pub fn dominant(weights: &[GLfloat; COUNT]) -> Material {
+    let mut best = 0;
+    for (index, &weight) in weights.iter().enumerate() {
+        if weight > weights[best] {
+            best = index;
+        }
+    }
+    Material::ALL[best]
+}
+
+/// The density-only view of a field returning density and material, which
+/// jobs without materials sample, along with the field itself.
+pub fn split(field: impl Fn(f64, f64, f64) -> (f64, Material) + Send + Sync + 'static) -> (Arc<ScalarField>, Arc<MaterialField>) {
+    let field: Arc<MaterialField> = Arc::new(field);
+    let density = field.clone();
+    (Arc::new(move |x, y, z| density(x, y, z).0), field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blend_weights() {
+        let blend = weights(vec![Material::Rock, Material::Snow, Material::Snow, Material::Snow]);
+        assert!(blend == [0.25, 0.0, 0.75, 0.0]);
+        assert!(dominant(&blend) == Material::Snow);
+        assert!(weights(Vec::new()) == Material::Rock.weights());
+        assert!(Material::from_index(3) == Some(Material::Ice));
+        assert!(Material::from_index(4).is_none());
+
+        let (density, material) = split(|x, _, _| (x, if x < 0.0 { Material::Sand } else { Material::Ice }));
+        assert!(density(-1.0, 0.0, 0.0) == -1.0);
+        assert!(material(-1.0, 0.0, 0.0) == (-1.0, Material::Sand));
+    }
+}
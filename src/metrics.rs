@@ -0,0 +1,227 @@
+#![allow(dead_code)]
+
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use crate::error::Error;
+
+/// Counts values in power-of-two buckets: bucket 0 holds zeros and bucket
+/// `i` holds values in `[2^(i-1), 2^i)`. Percentiles are approximate, exact
+/// to within a factor of two, which is plenty to tell 1ms chunks from 30ms
+/// ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; 65],
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram { buckets: [0; 65], count: 0, sum: 0, min: u64::MAX, max: 0 }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        let bucket = (64 - value.leading_zeros()) as usize;
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    pub fn min(&self) -> Option<u64> {
+        if self.count > 0 { Some(self.min) } else { None }
+    }
+
+    pub fn max(&self) -> Option<u64> {
+        if self.count > 0 { Some(self.max) } else { None }
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        if self.count > 0 { Some(self.sum as f64 / self.count as f64) } else { None }
+    }
+
+    /// Upper bound of the bucket holding the `p`th percentile (`p` in 0..=1),
+    /// clamped to the largest value recorded.
+    pub fn percentile(&self, p: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((p.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let upper = if bucket == 0 { 0 } else { (1u64 << (bucket - 1)).saturating_mul(2) - 1 };
+                return Some(upper.min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// Non-empty buckets as `(lowest value, highest value, count)`.
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, u64, u64)> + '_ {
+        self.buckets.iter().enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(bucket, &count)| match bucket {
+                0 => (0, 0, count),
+                _ => (1 << (bucket - 1), (1u64 << (bucket - 1)).saturating_mul(2) - 1, count),
+            })
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.mean(), self.percentile(0.5), self.percentile(0.9), self.percentile(0.99), self.max()) {
+            (Some(mean), Some(p50), Some(p90), Some(p99), Some(max)) =>
+                write!(f, "n={} mean={:.0} p50<={} p90<={} p99<={} max={}", self.count, mean, p50, p90, p99, max),
+            _ => write!(f, "n=0"),
+        }
+    }
+}
+
+/// Chunks generated at one level.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LevelThroughput {
+    pub chunks: u64,
+    pub vertices: u64,
+    /// Time threads spent generating these chunks.
+    pub busy: Duration,
+}
+
+impl LevelThroughput {
+    /// Chunks per second of thread time.
+    pub fn chunks_per_second(&self) -> f64 {
+        self.chunks as f64 / self.busy.as_secs_f64().max(1e-9)
+    }
+}
+
+/// How long a finished mesh task took at each stage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Timing {
+    pub queue_wait: Duration,
+    /// Time spent in the scalar field. Unknown for remote workers, and
+    /// unless field timing is on.
+    pub field: Option<Duration>,
+    /// Time from picking the task up to having its mesh.
+    pub total: Duration,
+}
+
+/// Timing and size statistics of the mesh tasks a worker has run, from
+/// `Worker::metrics`. Durations are recorded in microseconds.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WorkerMetrics {
+    /// Time from being queued to being picked up by a thread.
+    pub queue_wait: Histogram,
+    /// Time spent evaluating the scalar field, only recorded while
+    /// `Pool::set_field_timing` is on.
+    pub field: Histogram,
+    /// Time spent meshing, outside the scalar field when it is timed. For
+    /// remote workers this is the whole round trip.
+    pub meshing: Histogram,
+    pub vertices: Histogram,
+    /// Indexed by level.
+    pub levels: Vec<LevelThroughput>,
+    /// Time since the worker started.
+    pub elapsed: Duration,
+}
+
+impl WorkerMetrics {
+    pub(crate) fn record(&mut self, level: i32, timing: Timing, vertices: usize) {
+        self.queue_wait.record(timing.queue_wait.as_micros() as u64);
+        let meshing = match timing.field {
+            Some(field) => {
+                self.field.record(field.as_micros() as u64);
+                timing.total.saturating_sub(field)
+            }
+            None => timing.total,
+        };
+        self.meshing.record(meshing.as_micros() as u64);
+        self.vertices.record(vertices as u64);
+
+        let level = level.max(0) as usize;
+        if self.levels.len() <= level {
+            self.levels.resize(level + 1, LevelThroughput::default());
+        }
+        let throughput = &mut self.levels[level];
+        throughput.chunks += 1;
+        throughput.vertices += vertices as u64;
+        throughput.busy += timing.total;
+    }
+
+    pub fn chunks(&self) -> u64 {
+        self.levels.iter().map(|throughput| throughput.chunks).sum()
+    }
+
+    /// Chunks generated per second of wall time.
+    pub fn chunks_per_second(&self) -> f64 {
+        self.chunks() as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+
+    /// Writes the report `Display` prints to `path`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut file = File::create(path)?;
+        write!(file, "{}", self)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for WorkerMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} chunks in {:.2}s ({:.1}/s)", self.chunks(), self.elapsed.as_secs_f64(), self.chunks_per_second())?;
+        writeln!(f, "queue wait (us): {}", self.queue_wait)?;
+        writeln!(f, "field (us):      {}", self.field)?;
+        writeln!(f, "meshing (us):    {}", self.meshing)?;
+        writeln!(f, "vertices:        {}", self.vertices)?;
+        for (level, throughput) in self.levels.iter().enumerate() {
+            if throughput.chunks > 0 {
+                writeln!(f, "level {:2}: {} chunks, {} vertices, {:.1} chunks/s per thread",
+                         level, throughput.chunks, throughput.vertices, throughput.chunks_per_second())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = Histogram::new();
+        assert!(histogram.percentile(0.5).is_none());
+        for value in 1..=100 {
+            histogram.record(value);
+        }
+        histogram.record(0);
+
+        assert!(histogram.count() == 101);
+        assert!(histogram.min() == Some(0));
+        assert!(histogram.max() == Some(100));
+        // 50 falls in [32, 63], 99 in [64, 127] clamped to the max
+        assert!(histogram.percentile(0.5) == Some(63));
+        assert!(histogram.percentile(0.99) == Some(100));
+        assert!(histogram.percentile(0.0) == Some(0));
+        assert!(histogram.buckets().map(|(_, _, count)| count).sum::<u64>() == 101);
+    }
+}
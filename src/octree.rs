@@ -1,41 +1,527 @@
 #![allow(dead_code)]
 
-use crate::geometry::Geometry;
-use cgmath::prelude::*;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
 use cgmath::{Vector3, Matrix4};
-use crate::shader::{Uniform};
-use gl::types::*;
-use gl;
-use crate::worker::{Worker, Task, TaskAction};
+use crate::backend::{MeshBackend, GlBackend};
+use crate::error::Error;
+use crate::geometry::Vertex;
+use crate::job::{ChunkHandle, Job, JobHandle};
+use crate::metrics::WorkerMetrics;
+use crate::queue::{self, Metric};
+use crate::worker::{self, Worker, Task, CancelStats};
 
-pub struct Octree {
-    pub(crate) root: OctreeNode,
-    pub(crate) info: OctreeInfo,
+/// Identifies a node by its level and integer coordinates within that level.
+///
+/// At level `l` the unit cube is split into `2^l` cells along each axis and
+/// `x`, `y`, `z` index those cells, so keys stay exact however deep the tree
+/// (up to `MAX_LEVEL`).
+///
+/// Coordinates outside `0..2^l` belong to the neighbouring unit cubes of an
+/// unbounded octree: the root at cell `(i, j, k)` of the world grid is
+/// centred on `(i, j, k)`, and its descendants are keyed the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeKey {
+    pub level: i32,
+    pub x: i64,
+    pub y: i64,
+    pub z: i64,
 }
 
-pub struct OctreeInfo {
-    worker: Worker,
+/// Deepest level whose coordinates still fit in a `NodeKey`.
+pub const MAX_LEVEL: i32 = 62;
+
+/// Edge length of nodes at `level`, exact for every level.
+#[inline]
+pub fn level_size(level: i32) -> f64 {
+    0.5f64.powi(level)
+}
+
+impl NodeKey {
+    #[inline]
+    pub fn root() -> NodeKey {
+        NodeKey { level: 0, x: 0, y: 0, z: 0 }
+    }
+
+    /// The root of the world grid cell `cell`, centred on it.
+    #[inline]
+    pub fn root_at(cell: RootCell) -> NodeKey {
+        NodeKey { level: 0, x: cell.0, y: cell.1, z: cell.2 }
+    }
+
+    /// The world grid cell whose root this node descends from.
+    #[inline]
+    pub fn root_cell(&self) -> RootCell {
+        (self.x >> self.level, self.y >> self.level, self.z >> self.level)
+    }
+
+    /// Child `index` in the order the octree stores them: bit 2 set for the
+    /// lower half along x, bit 1 along y, bit 0 along z.
+    #[inline]
+    pub fn child(&self, index: usize) -> NodeKey {
+        NodeKey {
+            level: self.level + 1,
+            x: self.x * 2 + if index & 4 == 0 { 1 } else { 0 },
+            y: self.y * 2 + if index & 2 == 0 { 1 } else { 0 },
+            z: self.z * 2 + if index & 1 == 0 { 1 } else { 0 },
+        }
+    }
+
+    /// Index of the child at `level` on the way from the root down to this
+    /// node. `level` must be between 1 and `self.level`.
+    #[inline]
+    pub fn child_index(&self, level: i32) -> usize {
+        let shift = self.level - level;
+        (if (self.x >> shift) & 1 == 1 { 0 } else { 4 }) |
+        (if (self.y >> shift) & 1 == 1 { 0 } else { 2 }) |
+        (if (self.z >> shift) & 1 == 1 { 0 } else { 1 })
+    }
+
+    /// Child indices from the root down to this node.
+    pub fn path(&self) -> Vec<i8> {
+        (1..=self.level).map(|level| self.child_index(level) as i8).collect()
+    }
+
+    #[inline]
+    pub fn size(&self) -> f64 {
+        level_size(self.level)
+    }
+
+    #[inline]
+    pub fn center(&self) -> (f64, f64, f64) {
+        let size = self.size();
+        (
+            (self.x as f64 + 0.5) * size - 0.5,
+            (self.y as f64 + 0.5) * size - 0.5,
+            (self.z as f64 + 0.5) * size - 0.5,
+        )
+    }
+}
+
+/// A cell of the integer grid the roots of an unbounded octree live on.
+pub type RootCell = (i64, i64, i64);
+
+pub struct Octree<B: MeshBackend = GlBackend> {
+    /// A single root at `(0, 0, 0)`, unless the tree is unbounded. Ordered so
+    /// walks visit roots in the same order every time.
+    pub(crate) roots: BTreeMap<RootCell, OctreeNode<B>>,
+    pub(crate) info: OctreeInfo<B>,
+    metric: Metric,
+    uploads: Uploads,
+    retain_vertices: bool,
+    /// Failures of current nodes, until `take_errors` hands them out.
+    errors: Vec<(NodeKey, Error)>,
+}
+
+/// Order in which `Octree::visit` and `Octree::nodes` reach nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    /// Parents before their children, so subtrees can be skipped.
+    PreOrder,
+    /// Children before their parents, like `Octree::walk`.
+    PostOrder,
+}
+
+/// What a visitor wants done after seeing a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visit {
+    Continue,
+    /// Don't descend into this node's children. Only meaningful in pre-order,
+    /// where the children haven't been visited yet.
+    SkipChildren,
+    /// End the traversal.
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeState {
+    /// Waiting for the worker.
+    Pending,
+    /// Refused by a full worker queue; `update` asks again later.
+    Deferred,
+    /// Has a mesh.
+    Ready,
+    /// Generating the mesh failed, e.g. because the field panicked;
+    /// `regenerate` asks again.
+    Failed,
+}
+
+pub type Visitor<'a, B> = dyn FnMut(&mut OctreeNode<B>, &OctreeInfo<B>, &NodeView) -> Visit + 'a;
+
+/// Called by `Octree::walk` with each node, its key, level and centre.
+pub type NodeCallback<'a, B> = dyn Fn(&mut OctreeNode<B>, &OctreeInfo<B>, NodeKey, i32, f64, f64, f64) + 'a;
+
+/// What a traversal tells about a node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NodeView {
+    pub key: NodeKey,
+    pub level: i32,
+    pub center: (f64, f64, f64),
+    pub size: f64,
+    pub state: NodeState,
+    pub has_children: bool,
+}
+
+impl NodeView {
+    fn new<B: MeshBackend>(node: &OctreeNode<B>, key: NodeKey) -> NodeView {
+        NodeView {
+            key,
+            level: key.level,
+            center: key.center(),
+            size: key.size(),
+            state: node.state(),
+            has_children: node.children.is_some(),
+        }
+    }
+}
+
+/// Iterator over the nodes of an octree, from `Octree::nodes`.
+pub struct Nodes<'a, B: MeshBackend> {
+    order: Order,
+    /// Nodes still to be yielded. In post-order the flag marks nodes whose
+    /// children are already on the stack above them.
+    stack: Vec<(&'a OctreeNode<B>, NodeKey, bool)>,
+    /// In pre-order, the node yielded last, whose children are pushed on the
+    /// next call unless `skip_children` is called first.
+    last: Option<(&'a OctreeNode<B>, NodeKey)>,
+}
+
+impl<'a, B: MeshBackend> Nodes<'a, B> {
+    /// Leaves out the descendants of the node yielded last. Has no effect in
+    /// post-order.
+    pub fn skip_children(&mut self) {
+        self.last = None;
+    }
+
+    fn push_children(&mut self, node: &'a OctreeNode<B>, key: NodeKey) {
+        if let Some(ref children) = node.children {
+            for (index, child) in children.iter().enumerate().rev() {
+                self.stack.push((child, key.child(index), false));
+            }
+        }
+    }
+}
+
+impl<'a, B: MeshBackend> Iterator for Nodes<'a, B> {
+    type Item = NodeView;
+
+    fn next(&mut self) -> Option<NodeView> {
+        if let Some((node, key)) = self.last.take() {
+            self.push_children(node, key);
+        }
+        loop {
+            let (node, key, expanded) = self.stack.pop()?;
+            match self.order {
+                Order::PreOrder => {
+                    self.last = Some((node, key));
+                    return Some(NodeView::new(node, key));
+                }
+                Order::PostOrder if expanded => return Some(NodeView::new(node, key)),
+                Order::PostOrder => {
+                    self.stack.push((node, key, true));
+                    self.push_children(node, key);
+                }
+            }
+        }
+    }
+}
+
+/// Limits how much `Octree::update` uploads per call. At least one chunk is
+/// uploaded per call regardless, so an oversized chunk can't stall the queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UploadBudget {
+    pub max_chunks: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub max_time: Option<Duration>,
+}
+
+pub(crate) struct PendingUpload {
+    pub key: NodeKey,
+    pub epoch: u64,
+    priority: f64,
+    pub data: Vec<Vertex>,
+}
+
+/// Finished meshes waiting for a frame with upload budget left. Shared by the
+/// tree types fed by a `Worker`.
+#[derive(Default)]
+pub(crate) struct Uploads {
+    pub budget: UploadBudget,
+    pending: Vec<PendingUpload>,
+    /// Chunks whose generation failed, by key and epoch, until the tree
+    /// marks their nodes.
+    failed: Vec<(NodeKey, u64, Error)>,
+}
+
+impl Uploads {
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Moves finished chunks out of the worker (running queued tasks first,
+    /// for synchronous workers).
+    pub fn collect(&mut self, worker: &Worker) {
+        worker.pump();
+        for result in worker.try_iter() {
+            match result.data {
+                Ok(data) => self.pending.push(PendingUpload { key: result.key, epoch: result.epoch, priority: 0.0, data }),
+                Err(err) => self.failed.push((result.key, result.epoch, err)),
+            }
+        }
+    }
+
+    /// Failures collected since the last call.
+    pub fn take_failed(&mut self) -> Vec<(NodeKey, u64, Error)> {
+        std::mem::take(&mut self.failed)
+    }
+
+    /// Hands pending chunks to `install`, highest priority first, as long as
+    /// the budget allows. `install` returns false for chunks whose node was
+    /// destroyed or recreated, which are counted in `stale`. It may take the
+    /// vertex data to keep it.
+    pub fn upload(&mut self, priority: impl Fn(NodeKey) -> f64, stale: &Cell<usize>, mut install: impl FnMut(&mut PendingUpload) -> bool) {
+        for upload in self.pending.iter_mut() {
+            upload.priority = priority(upload.key);
+        }
+        // Most urgent last, so uploads can pop from the end
+        self.pending.sort_by(|a, b| b.priority.partial_cmp(&a.priority).unwrap_or(Ordering::Equal));
+
+        let start = Instant::now();
+        let mut chunks = 0;
+        let mut bytes = 0;
+        while let Some(mut upload) = self.pending.pop() {
+            let size = upload.data.len() * std::mem::size_of::<Vertex>();
+            if !install(&mut upload) {
+                stale.set(stale.get() + 1);
+                continue;
+            }
+
+            chunks += 1;
+            bytes += size;
+            if self.budget.max_chunks.is_some_and(|max| chunks >= max) ||
+                self.budget.max_bytes.is_some_and(|max| bytes >= max) ||
+                self.budget.max_time.is_some_and(|max| start.elapsed() >= max) {
+                break;
+            }
+        }
+    }
+}
+
+pub struct OctreeInfo<B: MeshBackend> {
+    pub(crate) worker: Worker,
+    pub(crate) backend: B,
+    next_epoch: Cell<u64>,
+    pub(crate) stale: Cell<usize>,
+    /// Nodes whose generation request the worker refused because its queue
+    /// was full; `update` retries them.
+    pub(crate) deferred: Cell<usize>,
+    /// Keys and epochs of the deferred nodes, oldest first. Entries for nodes
+    /// freed or regenerated since are skipped, and purged once they outnumber
+    /// the others.
+    deferred_keys: RefCell<VecDeque<(NodeKey, u64)>>,
+}
+
+impl<B: MeshBackend> OctreeInfo<B> {
+    pub(crate) fn new(backend: B, worker: Worker) -> OctreeInfo<B> {
+        OctreeInfo {
+            worker,
+            backend,
+            next_epoch: Cell::new(0),
+            stale: Cell::new(0),
+            deferred: Cell::new(0),
+            deferred_keys: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    /// A new epoch for a node being created.
+    pub(crate) fn next_epoch(&self) -> u64 {
+        let epoch = self.next_epoch.get();
+        self.next_epoch.set(epoch + 1);
+        epoch
+    }
+
+    /// Sends `task` without waiting, counting it as deferred if the worker
+    /// refuses it. Returns whether it was accepted.
+    pub(crate) fn request(&self, task: Task) -> bool {
+        let (key, epoch) = (task.key, task.epoch);
+        let accepted = self.worker.try_send(task).is_ok();
+        if !accepted {
+            self.deferred.set(self.deferred.get() + 1);
+            self.deferred_keys.borrow_mut().push_back((key, epoch));
+        }
+        accepted
+    }
+
+    /// Re-sends deferred requests, oldest first, until the worker refuses
+    /// one. `node(key, epoch, send)` returns `None` unless the node `key` is
+    /// still deferred with `epoch`; otherwise, if `send` is set, it tries to
+    /// send the node's request and returns whether the worker accepted it.
+    pub(crate) fn retry_deferred(&self, mut node: impl FnMut(NodeKey, u64, bool) -> Option<bool>) {
+        let mut keys = self.deferred_keys.borrow_mut();
+        if self.deferred.get() == 0 {
+            keys.clear();
+            return;
+        }
+        if !self.worker.has_capacity() {
+            if keys.len() > 2 * self.deferred.get() {
+                keys.retain(|&(key, epoch)| node(key, epoch, false).is_some());
+            }
+            return;
+        }
+        while let Some((key, epoch)) = keys.pop_front() {
+            match node(key, epoch, true) {
+                Some(true) => self.deferred.set(self.deferred.get() - 1),
+                Some(false) => {
+                    keys.push_front((key, epoch));
+                    break;
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Records that the deferred node `key` was given a new epoch.
+    pub(crate) fn redefer(&self, key: NodeKey, epoch: u64) {
+        self.deferred_keys.borrow_mut().push_back((key, epoch));
+    }
 }
 
 impl Octree {
     #[inline]
-    pub fn new(scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + 'static) -> Octree {
-        let worker = Worker::spawn(scalar_field);
-        let info = OctreeInfo { worker };
-        Octree { root: OctreeNode::new(&info, &mut vec!(), 0, 0.0, 0.0, 0.0), info }
+    pub fn new(scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static) -> Octree {
+        Octree::with_backend(GlBackend, scalar_field)
     }
+}
 
-    pub fn walk(&mut self, callback: &(Fn(&mut OctreeNode, &OctreeInfo, &mut Vec<i8>, i32, f64, f64, f64))) {
-        self.root.walk(&self.info, callback, &mut vec!(), 0, 0.0, 0.0, 0.0);
+impl<B: MeshBackend> Octree<B> {
+    pub fn with_backend(backend: B, scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static) -> Octree<B> {
+        Octree::with_worker(backend, Worker::spawn(scalar_field))
+    }
+
+    /// Builds an octree fed by an existing worker, e.g. one with a custom
+    /// number of threads.
+    pub fn with_worker(backend: B, worker: Worker) -> Octree<B> {
+        let mut octree = Octree::unbounded(backend, worker);
+        octree.insert_root((0, 0, 0));
+        octree
+    }
+
+    /// Builds an octree without a fixed extent: it starts out empty and
+    /// `update_roots` allocates roots, each covering one unit cube of an
+    /// integer grid, around the camera as it moves.
+    ///
+    /// Keys of deep nodes far from the origin must still fit in an `i64`, so
+    /// a root at cell `c` can be subdivided to about `MAX_LEVEL - log2(|c|)`.
+    pub fn unbounded(backend: B, worker: Worker) -> Octree<B> {
+        Octree {
+            roots: BTreeMap::new(),
+            info: OctreeInfo::new(backend, worker),
+            metric: queue::by_level(),
+            uploads: Uploads::default(),
+            retain_vertices: false,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.info.backend
+    }
+
+    /// Calls `callback` on every node, children before their parents.
+    pub fn walk(&mut self, callback: &NodeCallback<'_, B>) {
+        self.visit(Order::PostOrder, |node, info, view| {
+            let (x, y, z) = view.center;
+            callback(node, info, view.key, view.level, x, y, z);
+            Visit::Continue
+        });
+    }
+
+    /// Calls `visitor` on the nodes in `order`, following what it returns.
+    /// In pre-order, children the visitor creates are visited next, so a
+    /// single pass can refine the tree as deep as it needs to go.
+    pub fn visit(&mut self, order: Order, mut visitor: impl FnMut(&mut OctreeNode<B>, &OctreeInfo<B>, &NodeView) -> Visit) {
+        for (&cell, root) in self.roots.iter_mut() {
+            if !root.visit(&self.info, NodeKey::root_at(cell), order, &mut visitor) {
+                break;
+            }
+        }
+    }
+
+    /// Iterates over the nodes in `order`, without changing them.
+    pub fn nodes(&self, order: Order) -> Nodes<'_, B> {
+        Nodes {
+            order,
+            stack: self.roots.iter().rev().map(|(&cell, root)| (root, NodeKey::root_at(cell), false)).collect(),
+            last: None,
+        }
+    }
+
+    /// Cells that currently have a root.
+    pub fn roots(&self) -> impl Iterator<Item = RootCell> + '_ {
+        self.roots.keys().cloned()
+    }
+
+    /// Allocates the root of `cell` (requesting its mesh) unless it exists.
+    pub fn insert_root(&mut self, cell: RootCell) {
+        if !self.roots.contains_key(&cell) {
+            let root = OctreeNode::new(&self.info, NodeKey::root_at(cell));
+            self.roots.insert(cell, root);
+        }
+    }
+
+    /// Frees the root of `cell` and everything below it. Returns whether it
+    /// existed.
+    pub fn remove_root(&mut self, cell: RootCell) -> bool {
+        match self.roots.remove(&cell) {
+            Some(mut root) => {
+                root.free(&self.info, NodeKey::root_at(cell));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Allocates roots for the grid cells within `radius` of `camera` and
+    /// frees those more than `radius + 1` away, so a camera moving back and
+    /// forth across a cell boundary doesn't churn roots. Distances are to the
+    /// nearest point of each cell.
+    pub fn update_roots(&mut self, camera: (f64, f64, f64), radius: f64) {
+        let distance = |cell: RootCell| {
+            let axis = |c: i64, p: f64| ((p - c as f64).abs() - 0.5).max(0.0);
+            let (dx, dy, dz) = (axis(cell.0, camera.0), axis(cell.1, camera.1), axis(cell.2, camera.2));
+            (dx * dx + dy * dy + dz * dz).sqrt()
+        };
+
+        let far: Vec<RootCell> = self.roots.keys().cloned().filter(|&cell| distance(cell) > radius + 1.0).collect();
+        for cell in far {
+            self.remove_root(cell);
+        }
+
+        let reach = radius.max(0.0).ceil() as i64 + 1;
+        let (cx, cy, cz) = (camera.0.round() as i64, camera.1.round() as i64, camera.2.round() as i64);
+        for x in cx - reach..=cx + reach {
+            for y in cy - reach..=cy + reach {
+                for z in cz - reach..=cz + reach {
+                    if distance((x, y, z)) <= radius {
+                        self.insert_root((x, y, z));
+                    }
+                }
+            }
+        }
     }
 
-    pub fn draw(&mut self, parent_model_view: Matrix4<GLfloat>) {
-        self.root.walk(&self.info, &|node, _info, _path, level, x, y, z| {
+    /// Draws the tree. `parent_model_view` is kept in `f64` and combined with
+    /// each node's transform before converting to `f32`, so when it is
+    /// camera-relative, deep nodes near the camera keep their precision.
+    pub fn draw(&mut self, parent_model_view: Matrix4<f64>) {
+        self.walk(&|node, info, _key, level, x, y, z| {
             let mut should_draw = false;
             match &node.children {
                 &Some(ref children) => {
                     for child in children.as_ref() {
-                        if child.geometry.is_none() {
+                        if child.mesh.is_none() {
                             should_draw = true;
                         }
                     }
@@ -47,136 +533,599 @@ impl Octree {
                 return;
             }
 
-            let model_view: Matrix4<GLfloat> =
+            let model_view: Matrix4<f64> =
                 parent_model_view *
-                Matrix4::from_translation(Vector3::new(x as GLfloat, y as GLfloat, z as GLfloat)) *
-                Matrix4::from_scale(1.0 / GLfloat::from((1 << level) as i16));
+                Matrix4::from_translation(Vector3::new(x, y, z)) *
+                Matrix4::from_scale(level_size(level));
 
-            unsafe {
-                gl::UniformMatrix4fv(Uniform::ModelView as GLint,
-                                    1,
-                                    gl::FALSE,
-                                    model_view.as_ptr());
+            if let Some(ref mesh) = node.mesh {
+                info.backend.draw(mesh, model_view.cast());
             }
+        });
+    }
+
+    /// Sets the metric deciding which pending chunks are generated, and then
+    /// uploaded, first.
+    pub fn set_priority(&mut self, metric: Metric) {
+        self.metric = metric.clone();
+        self.info.worker.set_priority(metric);
+    }
+
+    pub fn set_upload_budget(&mut self, budget: UploadBudget) {
+        self.uploads.budget = budget;
+    }
+
+    /// Keeps a copy of each uploaded chunk's vertices in its node, so
+    /// queries like `raycast` can use the meshes instead of the field. Only
+    /// affects chunks uploaded from now on.
+    pub fn set_retain_vertices(&mut self, retain: bool) {
+        self.retain_vertices = retain;
+    }
+
+    /// Number of finished chunks waiting to be uploaded.
+    pub fn pending_uploads(&self) -> usize {
+        self.uploads.len()
+    }
+
+    /// Cancellation counters from the worker, plus the results `update` threw
+    /// away because their node was destroyed or recreated in the meantime.
+    pub fn cancel_stats(&self) -> CancelStats {
+        CancelStats { stale: self.info.stale.get(), ..self.info.worker.cancel_stats() }
+    }
+
+    /// Timing and throughput statistics from the worker.
+    pub fn metrics(&self) -> WorkerMetrics {
+        self.info.worker.metrics()
+    }
+
+    /// Errors of the chunks that failed to generate since the last call.
+    /// Their nodes stay `NodeState::Failed` until regenerated.
+    pub fn take_errors(&mut self) -> Vec<(NodeKey, Error)> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Generates the mesh for `key` on this octree's worker, outside the
+    /// tree: the mesh is only delivered to the returned handle.
+    pub fn request(&self, key: NodeKey) -> ChunkHandle {
+        self.info.worker.request(key)
+    }
 
-            if let Some(ref geometry) = node.geometry {
-                geometry.draw();
+    /// Runs `job` for the node `key` on this octree's worker.
+    pub fn submit<J: Job>(&self, key: NodeKey, job: J) -> JobHandle<J::Output> {
+        self.info.worker.submit(key, job)
+    }
+
+    /// Blocks until the worker finishes the next chunk and returns its mesh
+    /// data without uploading it, for tools running without a GL context.
+    pub fn next_result(&self) -> Option<worker::Result> {
+        self.info.worker.recv()
+    }
+
+    /// Re-sends generation requests the worker refused while its queue was
+    /// full. Called by `update`; tools that bypass it call this themselves.
+    pub fn retry_deferred(&mut self) {
+        let info = &self.info;
+        let roots = &mut self.roots;
+        info.retry_deferred(|key, epoch, send| {
+            match roots.get_mut(&key.root_cell()).and_then(|root| root.find_mut(key)) {
+                Some(node) if node.epoch == epoch && !node.requested => {
+                    if send {
+                        node.requested = info.worker.try_send(Task::generate(key, epoch)).is_ok();
+                    }
+                    Some(node.requested)
+                }
+                _ => None,
             }
-        }, &mut vec!(), 0, 0.0, 0.0, 0.0);
+        });
     }
 
+    /// Collects finished chunks from the worker (running queued tasks first,
+    /// for synchronous workers) and uploads as many as the
+    /// upload budget allows, highest priority first.
     pub fn update(&mut self) {
-        for result in self.info.worker.try_iter() {
-            let geometry = Geometry::from(result.data.as_ref());
-            self.root.update(&result.path, 0, geometry);
+        self.uploads.collect(&self.info.worker);
+        for (key, epoch, error) in self.uploads.take_failed() {
+            match self.roots.get_mut(&key.root_cell()).and_then(|root| root.find_mut(key)) {
+                Some(node) if node.epoch == epoch => {
+                    node.failed = true;
+                    self.errors.push((key, error));
+                }
+                _ => self.info.stale.set(self.info.stale.get() + 1),
+            }
         }
+        self.retry_deferred();
+
+        let metric = &self.metric;
+        let roots = &mut self.roots;
+        let backend = &self.info.backend;
+        let retain_vertices = self.retain_vertices;
+        self.uploads.upload(|key| {
+            let (x, y, z) = key.center();
+            metric(key.level, x, y, z)
+        }, &self.info.stale, |upload| {
+            match roots.get_mut(&upload.key.root_cell()).and_then(|root| root.find_mut(upload.key)) {
+                Some(node) if node.epoch == upload.epoch => {
+                    let mesh = backend.upload(upload.data.as_ref());
+                    if let Some(old) = node.mesh.replace(mesh) {
+                        backend.free(old);
+                    }
+                    node.vertices = if retain_vertices { Some(std::mem::take(&mut upload.data)) } else { None };
+                    node.outdated = false;
+                    true
+                }
+                _ => false,
+            }
+        });
     }
 }
 
-pub struct OctreeNode {
-    pub geometry: Option<Geometry>,
-    pub children: Option<Box<[OctreeNode; 8]>>,
+pub struct OctreeNode<B: MeshBackend> {
+    pub mesh: Option<B::Mesh>,
+    /// The mesh's vertices, in the node's local coordinates, when the tree
+    /// retains them.
+    pub vertices: Option<Vec<Vertex>>,
+    pub children: Option<Box<[OctreeNode<B>; 8]>>,
+    /// Distinguishes this node from earlier ones created under the same key,
+    /// so late results for those can be recognised and dropped.
+    pub epoch: u64,
+    /// Whether the worker accepted this node's generation task.
+    pub requested: bool,
+    /// Whether `mesh` predates a `regenerate` and a new one is on its way.
+    pub outdated: bool,
+    /// Whether the worker failed to generate the mesh requested last.
+    pub failed: bool,
 }
 
-impl OctreeNode {
+impl<B: MeshBackend> OctreeNode<B> {
     #[inline]
-    pub fn new(info: &OctreeInfo, path: &Vec<i8>, level: i32, x: f64, y: f64, z: f64) -> OctreeNode {
-        info.worker.send(Task {
-            action: TaskAction::Generate,
-            path: path.clone(),
-            level,
-            x,
-            y,
-            z,
-        });
-        OctreeNode { geometry: None, children: None }
-    }
-
-    fn walk(&mut self, info: &OctreeInfo, callback: &Fn(&mut OctreeNode, &OctreeInfo, &mut Vec<i8>, i32, f64, f64, f64), path: &mut Vec<i8>, level: i32, x: f64, y: f64, z: f64) {
-        let next_level = level + 1;
-        let inc = 0.5 / f64::from(1 << next_level);
-        match &mut self.children {
-            &mut Some(ref mut children) => {
-                path.push(0);
-                children[0].walk(info, callback, path, next_level, x + inc, y + inc, z + inc);
-                path.pop();
-                path.push(1);
-                children[1].walk(info, callback, path, next_level, x + inc, y + inc, z - inc);
-                path.pop();
-                path.push(2);
-                children[2].walk(info, callback, path, next_level, x + inc, y - inc, z + inc);
-                path.pop();
-                path.push(3);
-                children[3].walk(info, callback, path, next_level, x + inc, y - inc, z - inc);
-                path.pop();
-                path.push(4);
-                children[4].walk(info, callback, path, next_level, x - inc, y + inc, z + inc);
-                path.pop();
-                path.push(5);
-                children[5].walk(info, callback, path, next_level, x - inc, y + inc, z - inc);
-                path.pop();
-                path.push(6);
-                children[6].walk(info, callback, path, next_level, x - inc, y - inc, z + inc);
-                path.pop();
-                path.push(7);
-                children[7].walk(info, callback, path, next_level, x - inc, y - inc, z - inc);
-                path.pop();
-            }
-            &mut None => {}
-        }
-
-        callback(self, info, path, level, x, y, z);
+    pub fn new(info: &OctreeInfo<B>, key: NodeKey) -> OctreeNode<B> {
+        let epoch = info.next_epoch();
+        let requested = info.request(Task::generate(key, epoch));
+        OctreeNode { mesh: None, vertices: None, children: None, epoch, requested, outdated: false, failed: false }
     }
 
+    /// Whether a generation task for this node is queued or running.
     #[inline]
-    pub fn create_children(&mut self, info: &OctreeInfo, path: &mut Vec<i8>, level: i32, x: f64, y: f64, z: f64) {
+    fn pending(&self) -> bool {
+        self.requested && !self.failed && (self.mesh.is_none() || self.outdated)
+    }
+
+    /// Asks the worker for a new mesh, e.g. after the field changed around
+    /// this node. The current mesh stays until the new one is uploaded, and
+    /// any result still on its way for the old one is dropped as stale.
+    pub fn regenerate(&mut self, info: &OctreeInfo<B>, key: NodeKey) {
+        self.epoch = info.next_epoch();
+        self.outdated = self.mesh.is_some();
+        self.failed = false;
+        // Deferred nodes are sent with the new epoch when they're retried
+        if self.requested {
+            self.requested = info.request(Task::generate(key, self.epoch));
+        } else {
+            info.redefer(key, self.epoch);
+        }
+    }
+
+    pub fn state(&self) -> NodeState {
+        match (&self.mesh, self.requested) {
+            (Some(_), _) => NodeState::Ready,
+            (None, _) if self.failed => NodeState::Failed,
+            (None, true) => NodeState::Pending,
+            (None, false) => NodeState::Deferred,
+        }
+    }
+
+    /// Returns false once the visitor asked to stop.
+    fn visit(&mut self, info: &OctreeInfo<B>, key: NodeKey, order: Order, visitor: &mut Visitor<'_, B>) -> bool {
+        if order == Order::PreOrder {
+            let view = NodeView::new(self, key);
+            match visitor(self, info, &view) {
+                Visit::Continue => {}
+                Visit::SkipChildren => return true,
+                Visit::Stop => return false,
+            }
+        }
+
+        if let Some(ref mut children) = self.children {
+            for (index, child) in children.iter_mut().enumerate() {
+                if !child.visit(info, key.child(index), order, visitor) {
+                    return false;
+                }
+            }
+        }
+
+        if order == Order::PostOrder {
+            let view = NodeView::new(self, key);
+            if visitor(self, info, &view) == Visit::Stop {
+                return false;
+            }
+        }
+        true
+    }
+
+    #[inline]
+    pub fn create_children(&mut self, info: &OctreeInfo<B>, key: NodeKey, _level: i32, _x: f64, _y: f64, _z: f64) {
         if self.children.is_none() {
-            let next_level = level + 1;
-            let inc = 0.5 / f64::from(1 << next_level);
             self.children = Some(Box::from([
-                { path.push(0); let node = OctreeNode::new(info, path, next_level, x + inc, y + inc, z + inc); path.pop(); node },
-                { path.push(1); let node = OctreeNode::new(info, path, next_level, x + inc, y + inc, z - inc); path.pop(); node },
-                { path.push(2); let node = OctreeNode::new(info, path, next_level, x + inc, y - inc, z + inc); path.pop(); node },
-                { path.push(3); let node = OctreeNode::new(info, path, next_level, x + inc, y - inc, z - inc); path.pop(); node },
-                { path.push(4); let node = OctreeNode::new(info, path, next_level, x - inc, y + inc, z + inc); path.pop(); node },
-                { path.push(5); let node = OctreeNode::new(info, path, next_level, x - inc, y + inc, z - inc); path.pop(); node },
-                { path.push(6); let node = OctreeNode::new(info, path, next_level, x - inc, y - inc, z + inc); path.pop(); node },
-                { path.push(7); let node = OctreeNode::new(info, path, next_level, x - inc, y - inc, z - inc); path.pop(); node },
+                OctreeNode::new(info, key.child(0)),
+                OctreeNode::new(info, key.child(1)),
+                OctreeNode::new(info, key.child(2)),
+                OctreeNode::new(info, key.child(3)),
+                OctreeNode::new(info, key.child(4)),
+                OctreeNode::new(info, key.child(5)),
+                OctreeNode::new(info, key.child(6)),
+                OctreeNode::new(info, key.child(7)),
             ]));
         }
     }
 
     #[inline]
-    pub fn destroy_children(&mut self, info: &OctreeInfo, path: &mut Vec<i8>, level: i32, x: f64, y: f64, z: f64) {
-        match &mut self.children {
-            &mut Some(ref children) => {
-                for child in children.as_ref() {
-                    if child.geometry.is_none() {
-                        info.worker.send(Task {
-                            action: TaskAction::Cancel,
-                            path: path.clone(),
-                            level,
-                            x,
-                            y,
-                            z,
-                        });
+    pub fn destroy_children(&mut self, info: &OctreeInfo<B>, key: NodeKey, _level: i32, _x: f64, _y: f64, _z: f64) {
+        if let Some(mut children) = self.children.take() {
+            for (index, child) in children.iter_mut().enumerate() {
+                child.free(info, key.child(index));
+            }
+        }
+    }
+
+    /// Hands this node's mesh and those of all its descendants back to the
+    /// backend, cancelling generation for any that haven't finished yet.
+    fn free(&mut self, info: &OctreeInfo<B>, key: NodeKey) {
+        self.vertices = None;
+        if !self.requested {
+            info.deferred.set(info.deferred.get() - 1);
+        } else if self.pending() {
+            let _ = info.worker.send(Task::cancel(key, self.epoch));
+        }
+        if let Some(mesh) = self.mesh.take() {
+            info.backend.free(mesh);
+        }
+        if let Some(mut children) = self.children.take() {
+            for (index, child) in children.iter_mut().enumerate() {
+                child.free(info, key.child(index));
+            }
+        }
+    }
+
+    /// Finds the descendant (or this node, for its root's key) with `key`.
+    pub fn find_mut(&mut self, key: NodeKey) -> Option<&mut OctreeNode<B>> {
+        let mut node = self;
+        for level in 1..=key.level {
+            node = match node.children {
+                Some(ref mut children) => &mut children[key.child_index(level)],
+                None => return None,
+            };
+        }
+        Some(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{self, AtomicBool};
+    use std::time::{Duration, Instant};
+    use cgmath::One;
+    use crate::backend::{Event, RecordingBackend};
+    use crate::worker::QueueLimits;
+
+    fn sphere(x: f64, y: f64, z: f64) -> f64 {
+        x.powi(2) + y.powi(2) + z.powi(2) - 0.2
+    }
+
+    fn update_until(octree: &mut Octree<RecordingBackend>, uploads: usize) {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while octree.backend().uploads() < uploads {
+            assert!(Instant::now() < deadline, "Timed out waiting for the worker");
+            octree.update();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn synchronous() -> Octree<RecordingBackend> {
+        Octree::with_worker(RecordingBackend::new(), Worker::synchronous(sphere))
+    }
+
+    #[test]
+    fn upload_draw_free() {
+        let mut octree = synchronous();
+        octree.update();
+        assert!(octree.backend().uploads() == 1);
+
+        octree.walk(&|node, info, key, level, x, y, z| {
+            if level == 0 {
+                node.create_children(info, key, level, x, y, z);
+            }
+        });
+        octree.update();
+        assert!(octree.backend().resident() == 9);
+
+        octree.draw(One::one());
+        assert!(octree.backend().draws() == 8);
+
+        octree.walk(&|node, info, key, level, x, y, z| {
+            node.destroy_children(info, key, level, x, y, z);
+        });
+        assert!(octree.backend().frees() == 8);
+        assert!(octree.backend().resident() == 1);
+    }
+
+    #[test]
+    fn upload_budget() {
+        let mut octree = Octree::with_backend(RecordingBackend::new(), sphere);
+        octree.set_upload_budget(UploadBudget { max_chunks: Some(1), ..Default::default() });
+        octree.walk(&|node, info, key, level, x, y, z| {
+            node.create_children(info, key, level, x, y, z);
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while octree.backend().uploads() < 9 {
+            assert!(Instant::now() < deadline, "Timed out waiting for the worker");
+            let before = octree.backend().uploads();
+            octree.update();
+            assert!(octree.backend().uploads() - before <= 1);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(octree.pending_uploads() == 0);
+    }
+
+    #[test]
+    fn deferred_requests() {
+        let limits = QueueLimits { tasks: 2, results: 1 };
+        let mut octree = Octree::with_worker(RecordingBackend::new(), Worker::with_limits(1, limits, sphere));
+        octree.walk(&|node, info, key, level, x, y, z| {
+            node.create_children(info, key, level, x, y, z);
+        });
+        octree.walk(&|node, info, key, level, x, y, z| {
+            if level == 1 {
+                node.create_children(info, key, level, x, y, z);
+            }
+        });
+        assert!(octree.info.deferred.get() > 0);
+
+        update_until(&mut octree, 73);
+        assert!(octree.info.deferred.get() == 0);
+        assert!(octree.info.worker.queue_metrics().high_water <= 2);
+
+        // Entries left by deferred nodes that were freed are dropped
+        octree.walk(&|node, info, key, level, x, y, z| {
+            if level == 0 {
+                node.destroy_children(info, key, level, x, y, z);
+                node.create_children(info, key, level, x, y, z);
+            }
+        });
+        update_until(&mut octree, 81);
+        octree.retry_deferred();
+        assert!(octree.info.deferred.get() == 0);
+        assert!(octree.info.deferred_keys.borrow().is_empty());
+    }
+
+    #[test]
+    fn upload_order() {
+        let mut octree = synchronous();
+        octree.set_priority(queue::by_distance(1.0, 1.0, 1.0));
+        octree.set_upload_budget(UploadBudget { max_chunks: Some(1), ..Default::default() });
+        octree.walk(&|node, info, key, level, x, y, z| {
+            node.create_children(info, key, level, x, y, z);
+        });
+
+        // The root and the child nearest (1, 1, 1) go first, the farthest last
+        octree.update();
+        octree.update();
+        assert!(octree.roots[&(0, 0, 0)].mesh.is_some());
+        assert!(octree.roots[&(0, 0, 0)].children.as_ref().unwrap()[0].mesh.is_some());
+        for _ in 0..6 {
+            octree.update();
+        }
+        assert!(octree.roots[&(0, 0, 0)].children.as_ref().unwrap()[7].mesh.is_none());
+        octree.update();
+        assert!(octree.roots[&(0, 0, 0)].children.as_ref().unwrap()[7].mesh.is_some());
+    }
+
+    #[test]
+    fn deterministic() {
+        let run = || {
+            let mut octree = synchronous();
+            for _ in 0..3 {
+                octree.walk(&|node, info, key, level, x, y, z| {
+                    let (cx, cy, cz) = (0.3, 0.3, 0.0);
+                    let half = 0.5 * key.size();
+                    if (cx - x).abs() <= half && (cy - y).abs() <= half && (cz - z).abs() <= half {
+                        node.create_children(info, key, level, x, y, z);
+                    } else {
+                        node.destroy_children(info, key, level, x, y, z);
                     }
-                }
+                });
+                octree.update();
+                octree.draw(One::one());
             }
-            &mut None => {}
+            octree.backend().events()
+        };
+
+        let events = run();
+        assert!(events.len() > 0);
+        assert!(events == run());
+    }
+
+    #[test]
+    fn keys() {
+        let key = NodeKey::root().child(3).child(6);
+        assert!(key == NodeKey { level: 2, x: 2, y: 0, z: 1 });
+        assert!(key.path() == vec![3, 6]);
+        assert!(key.center() == (0.125, -0.375, -0.125));
+    }
+
+    #[test]
+    fn traversal() {
+        let mut octree = synchronous();
+        // Subdivide the root and its first child in one pre-order pass
+        octree.visit(Order::PreOrder, |node, info, view| {
+            if view.level < 2 && view.key.path().iter().all(|&index| index == 0) {
+                let (x, y, z) = view.center;
+                node.create_children(info, view.key, view.level, x, y, z);
+            }
+            Visit::Continue
+        });
+        assert!(octree.nodes(Order::PreOrder).count() == 17);
+        assert!(octree.nodes(Order::PreOrder).all(|view| view.state == NodeState::Pending));
+        octree.update();
+        assert!(octree.nodes(Order::PostOrder).all(|view| view.state == NodeState::Ready));
+
+        let pre: Vec<Vec<i8>> = octree.nodes(Order::PreOrder).map(|view| view.key.path()).collect();
+        assert!(pre[..3] == [vec![], vec![0], vec![0, 0]]);
+        assert!(pre[10] == vec![1]);
+        let post: Vec<Vec<i8>> = octree.nodes(Order::PostOrder).map(|view| view.key.path()).collect();
+        assert!(post[..2] == [vec![0, 0], vec![0, 1]]);
+        assert!(post[8] == vec![0]);
+        assert!(post[16] == vec![]);
+
+        // Skipping the first child's subtree leaves out its 8 children
+        let mut nodes = octree.nodes(Order::PreOrder);
+        let mut count = 0;
+        while let Some(view) = nodes.next() {
+            if view.level == 1 {
+                nodes.skip_children();
+            }
+            count += 1;
         }
+        assert!(count == 9);
+
+        let mut visited = 0;
+        octree.visit(Order::PostOrder, |_node, _info, view| {
+            visited += 1;
+            if view.key.path() == vec![0] { Visit::Stop } else { Visit::Continue }
+        });
+        assert!(visited == 9);
 
-        self.children = None;
+        let root = octree.nodes(Order::PreOrder).next().unwrap();
+        assert!(root.center == (0.0, 0.0, 0.0) && root.size == 1.0 && root.has_children);
     }
 
-    pub fn update(&mut self, path: &Vec<i8>, level: i32, geometry: Geometry) {
-        if level as usize == path.len() {
-            self.geometry = Some(geometry);
-        } else {
-            match self.children {
-                Some(ref mut children) => { children[path[level as usize] as usize].update(path, level + 1, geometry) }
-                None => {}
+    #[test]
+    fn unbounded() {
+        let mut octree = Octree::unbounded(RecordingBackend::new(), Worker::synchronous(|x, y, z| {
+            // A sphere around every grid point
+            (x - x.round()).powi(2) + (y - y.round()).powi(2) + (z - z.round()).powi(2) - 0.1
+        }));
+        octree.update_roots((-3.2, 0.0, 7.0), 0.4);
+        let roots: Vec<RootCell> = octree.roots().collect();
+        assert!(roots == vec![(-4, 0, 7), (-3, 0, 7)]);
+
+        let key = NodeKey::root_at((-4, 0, 7)).child(0).child(7);
+        assert!(key.root_cell() == (-4, 0, 7));
+        assert!(key.center() == (-3.875, 0.125, 7.125));
+        assert!(key.path() == vec![0, 7]);
+
+        octree.walk(&|node, info, key, level, x, y, z| {
+            if key.root_cell() == (-4, 0, 7) {
+                node.create_children(info, key, level, x, y, z);
             }
+        });
+        octree.update();
+        assert!(octree.backend().resident() == 10);
+        assert!(octree.info.stale.get() == 0);
+
+        // Within a cell of the old roots nothing is freed; far away they all are
+        octree.update_roots((-2.55, 0.0, 7.0), 0.1);
+        assert!(octree.roots().count() == 3);
+        octree.update_roots((20.0, 0.0, 0.0), 0.1);
+        octree.update();
+        assert!(octree.roots().collect::<Vec<_>>() == vec![(20, 0, 0)]);
+        assert!(octree.backend().resident() == 1);
+    }
+
+    #[test]
+    fn deep_levels() {
+        let level = 24;
+        let target = NodeKey {
+            level,
+            x: ((0.2f64.sqrt() + 0.5) / level_size(level)) as i64,
+            y: 1 << (level - 1),
+            z: 1 << (level - 1),
+        };
+        assert!(level_size(MAX_LEVEL) > 0.0);
+        assert!(target.size() == 1.0 / 16777216.0);
+
+        let mut octree = synchronous();
+        for _ in 0..level {
+            octree.walk(&|node, info, key, level, x, y, z| {
+                let shift = target.level - key.level;
+                if shift > 0 && target.x >> shift == key.x && target.y >> shift == key.y && target.z >> shift == key.z {
+                    node.create_children(info, key, level, x, y, z);
+                }
+            });
         }
+        octree.update();
+        assert!(octree.backend().resident() == 1 + 8 * level as usize);
+
+        // Drawn relative to a camera at the target's centre, the target ends
+        // up exactly at the origin instead of rounded to the nearest `f32`
+        let (x, y, z) = target.center();
+        octree.draw(Matrix4::from_translation(Vector3::new(-x, -y, -z)));
+        let at_camera = octree.backend().events().iter().any(|event| match event {
+            Event::Draw(_, model_view) => model_view.w.x == 0.0 && model_view.w.y == 0.0 && model_view.w.z == 0.0 &&
+                model_view.x.x == target.size() as f32,
+            _ => false,
+        });
+        assert!(at_camera);
+    }
+
+    #[test]
+    fn stale_results_dropped() {
+        let worker = Worker::with_threads(1, sphere);
+        let mut octree = Octree::with_worker(RecordingBackend::new(), worker);
+        update_until(&mut octree, 1);
+
+        // Recreating the children before their first results arrive leaves
+        // those results with an outdated epoch
+        octree.walk(&|node, info, key, level, x, y, z| {
+            if level == 0 {
+                node.create_children(info, key, level, x, y, z);
+                node.destroy_children(info, key, level, x, y, z);
+                node.create_children(info, key, level, x, y, z);
+            }
+        });
+        update_until(&mut octree, 9);
+
+        let stats = octree.cancel_stats();
+        assert!(stats.requested == 8);
+        assert!(stats.dequeued + stats.discarded + stats.stale == 8);
+        assert!(octree.backend().resident() == 9);
+    }
+
+    #[test]
+    fn failed_generation() {
+        let broken = Arc::new(AtomicBool::new(true));
+        let field = {
+            let broken = broken.clone();
+            move |x: f64, y: f64, z: f64| {
+                if broken.load(atomic::Ordering::Relaxed) {
+                    panic!("bad field");
+                }
+                sphere(x, y, z)
+            }
+        };
+        let mut octree = Octree::with_worker(RecordingBackend::new(), Worker::synchronous(field));
+        octree.update();
+        assert!(octree.nodes(Order::PreOrder).all(|view| view.state == NodeState::Failed));
+        let errors = octree.take_errors();
+        assert!(errors.len() == 1);
+        match errors[0] {
+            (key, Error::Panic(_, ref message)) => assert!(key == NodeKey::root() && message == "bad field"),
+            _ => panic!("Expected a panic error"),
+        }
+        assert!(octree.take_errors().is_empty());
+
+        // Failed nodes aren't retried until regenerated
+        octree.update();
+        assert!(octree.take_errors().is_empty());
+        broken.store(false, atomic::Ordering::Relaxed);
+        octree.visit(Order::PreOrder, |node, info, view| {
+            node.regenerate(info, view.key);
+            Visit::Continue
+        });
+        octree.update();
+        assert!(octree.nodes(Order::PreOrder).all(|view| view.state == NodeState::Ready));
+        assert!(octree.backend().uploads() == 1);
     }
 }
@@ -0,0 +1,557 @@
+#![allow(dead_code)]
+
+use std::cmp::Ordering;
+use cgmath::{InnerSpace, Vector3};
+use crate::backend::MeshBackend;
+use crate::geometry::Vertex;
+use crate::job::ScalarField;
+use crate::octree::{NodeKey, Octree, OctreeNode};
+
+/// Where a ray first meets the surface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+    pub point: Vector3<f64>,
+    /// Unit normal pointing out of the surface.
+    pub normal: Vector3<f64>,
+    /// Distance from the ray's origin to `point`.
+    pub distance: f64,
+    /// The node whose mesh, or part of the field, was hit.
+    pub node: NodeKey,
+}
+
+/// The surface point nearest to a query point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SurfacePoint {
+    pub point: Vector3<f64>,
+    /// Unit normal pointing out of the surface.
+    pub normal: Vector3<f64>,
+    /// Distance from the query point to `point`, negative when the query
+    /// point is inside the surface.
+    pub distance: f64,
+    /// The node whose mesh, or part of the field, `point` lies on.
+    pub node: NodeKey,
+}
+
+/// How a shape overlaps the surface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contact {
+    /// The surface point deepest inside the shape.
+    pub point: Vector3<f64>,
+    /// Unit normal pointing out of the surface: moving the shape `depth`
+    /// along it separates the two.
+    pub normal: Vector3<f64>,
+    /// Penetration depth, positive.
+    pub depth: f64,
+    pub node: NodeKey,
+}
+
+/// Field samples taken across a node when marching a ray through it, the
+/// resolution chunk meshes are generated at.
+const FIELD_STEPS: f64 = 16.0;
+
+/// Bisection steps refining a sign change of the field down to the surface.
+const BISECTIONS: usize = 40;
+
+/// Newton steps projecting a point onto the field's surface.
+const PROJECTION_STEPS: usize = 32;
+
+/// Most points of a capsule's axis tested before refining the closest one.
+const CAPSULE_SAMPLES: usize = 64;
+
+impl<B: MeshBackend> Octree<B> {
+    /// Casts a ray from `origin` along `dir` for up to `max_dist`, visiting
+    /// the deepest nodes along it front to back. Nodes holding retained
+    /// vertices (see `set_retain_vertices`) are intersected as meshes, the
+    /// others by marching the worker's scalar field through them, so the hit
+    /// matches what is drawn at the current LOD where it can. Nodes of a
+    /// remote worker's tree without retained vertices can't be hit.
+    ///
+    /// A ray starting inside the surface hits it at its origin. A zero `dir`
+    /// has no direction to cast along and hits nothing.
+    pub fn raycast(&self, origin: Vector3<f64>, dir: Vector3<f64>, max_dist: f64) -> Option<Hit> {
+        if dir.magnitude2() == 0.0 {
+            return None;
+        }
+        let field = self.info.worker.scalar_field();
+        let ray = Ray { origin, dir: dir.normalize(), max_dist, field: field.as_deref() };
+
+        let mut roots: Vec<(f64, f64, NodeKey, &OctreeNode<B>)> = self.roots.iter()
+            .map(|(&cell, root)| (NodeKey::root_at(cell), root))
+            .filter_map(|(key, root)| ray.interval(key).map(|(near, far)| (near, far, key, root)))
+            .collect();
+        roots.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+        roots.into_iter().find_map(|(near, far, key, root)| ray.node(root, key, near, far))
+    }
+
+    /// The surface point nearest to `point`, if one is within `max_dist`.
+    /// Like `raycast`, this uses the retained vertices of the deepest nodes
+    /// near `point` where there are any and the scalar field elsewhere. The
+    /// field is treated as roughly a distance field near its surface; fields
+    /// that are far from one give approximate results.
+    pub fn closest_point(&self, point: Vector3<f64>, max_dist: f64) -> Option<SurfacePoint> {
+        let mut leaves = Vec::new();
+        for (&cell, root) in self.roots.iter() {
+            leaves_near(root, NodeKey::root_at(cell), point, max_dist, &mut leaves);
+        }
+
+        let mut best: Option<SurfacePoint> = None;
+        let mut field_leaf: Option<NodeKey> = None;
+        for &(key, node) in &leaves {
+            match node.vertices {
+                Some(ref vertices) => {
+                    if let Some(candidate) = closest_on_mesh(vertices, key, point) {
+                        if best.is_none_or(|best| candidate.distance.abs() < best.distance.abs()) {
+                            best = Some(candidate);
+                        }
+                    }
+                }
+                None => {
+                    if field_leaf.is_none_or(|leaf| key.level > leaf.level) {
+                        field_leaf = Some(key);
+                    }
+                }
+            }
+        }
+
+        if let (Some(leaf), Some(field)) = (field_leaf, self.info.worker.scalar_field()) {
+            if let Some(candidate) = project(&*field, point, leaf.size() * 1e-3) {
+                let node = self.leaf_at(candidate.point).unwrap_or(leaf);
+                if best.is_none_or(|best| candidate.distance.abs() < best.distance.abs()) {
+                    best = Some(SurfacePoint { node, ..candidate });
+                }
+            }
+        }
+
+        best.filter(|best| best.distance <= max_dist)
+    }
+
+    /// How a sphere overlaps the surface, or `None` if it doesn't.
+    pub fn sphere_contact(&self, center: Vector3<f64>, radius: f64) -> Option<Contact> {
+        self.closest_point(center, radius).and_then(|closest| contact(closest, radius))
+    }
+
+    /// How the capsule around the segment from `a` to `b` overlaps the
+    /// surface, or `None` if it doesn't. The contact is taken at the point of
+    /// the segment nearest the surface.
+    pub fn capsule_contact(&self, a: Vector3<f64>, b: Vector3<f64>, radius: f64) -> Option<Contact> {
+        let length = (b - a).magnitude();
+        let at = |t: f64| a + (b - a) * t;
+        // Signed distance from the axis at `t`, or infinity when the surface
+        // is out of reach
+        let distance = |t: f64| self.closest_point(at(t), radius).map_or(f64::INFINITY, |closest| closest.distance);
+
+        let samples = ((2.0 * length / radius.max(f64::MIN_POSITIVE)).ceil() as usize).clamp(1, CAPSULE_SAMPLES);
+        let (mut nearest, mut nearest_distance) = (0.0, distance(0.0));
+        for sample in 1..=samples {
+            let t = sample as f64 / samples as f64;
+            let d = distance(t);
+            if d < nearest_distance {
+                nearest = t;
+                nearest_distance = d;
+            }
+        }
+
+        // Golden-section search between the neighbouring samples
+        let step = 1.0 / samples as f64;
+        let (mut low, mut high) = ((nearest - step).max(0.0), (nearest + step).min(1.0));
+        let ratio = 0.5 * (5.0f64.sqrt() - 1.0);
+        for _ in 0..BISECTIONS {
+            let (c, d) = (high - ratio * (high - low), low + ratio * (high - low));
+            if distance(c) < distance(d) {
+                high = d;
+            } else {
+                low = c;
+            }
+        }
+        let refined = 0.5 * (low + high);
+        let t = if distance(refined) < nearest_distance { refined } else { nearest };
+
+        self.closest_point(at(t), radius).and_then(|closest| contact(closest, radius))
+    }
+
+    pub fn overlaps_sphere(&self, center: Vector3<f64>, radius: f64) -> bool {
+        self.sphere_contact(center, radius).is_some()
+    }
+
+    pub fn overlaps_capsule(&self, a: Vector3<f64>, b: Vector3<f64>, radius: f64) -> bool {
+        self.capsule_contact(a, b, radius).is_some()
+    }
+
+    /// The deepest node containing `point`.
+    fn leaf_at(&self, point: Vector3<f64>) -> Option<NodeKey> {
+        let cell = (point.x.round() as i64, point.y.round() as i64, point.z.round() as i64);
+        let mut node = self.roots.get(&cell)?;
+        let mut key = NodeKey::root_at(cell);
+        while let Some(ref children) = node.children {
+            let (x, y, z) = key.center();
+            let index = (if point.x < x { 4 } else { 0 }) | (if point.y < y { 2 } else { 0 }) | (if point.z < z { 1 } else { 0 });
+            node = &children[index];
+            key = key.child(index);
+        }
+        Some(key)
+    }
+}
+
+/// Collects the deepest nodes under `node` within `radius` of `point`.
+fn leaves_near<'a, B: MeshBackend>(node: &'a OctreeNode<B>, key: NodeKey, point: Vector3<f64>, radius: f64, leaves: &mut Vec<(NodeKey, &'a OctreeNode<B>)>) {
+    let (x, y, z) = key.center();
+    let half = 0.5 * key.size();
+    let outside = |p: f64, c: f64| ((p - c).abs() - half).max(0.0);
+    let (dx, dy, dz) = (outside(point.x, x), outside(point.y, y), outside(point.z, z));
+    if dx * dx + dy * dy + dz * dz > radius * radius {
+        return;
+    }
+
+    match node.children {
+        Some(ref children) => {
+            for (index, child) in children.iter().enumerate() {
+                leaves_near(child, key.child(index), point, radius, leaves);
+            }
+        }
+        None => leaves.push((key, node)),
+    }
+}
+
+fn contact(closest: SurfacePoint, radius: f64) -> Option<Contact> {
+    let depth = radius - closest.distance;
+    if depth <= 0.0 {
+        return None;
+    }
+    Some(Contact { point: closest.point, normal: closest.normal, depth, node: closest.node })
+}
+
+/// Nearest point of the triangles in `vertices` to `point`.
+fn closest_on_mesh(vertices: &[Vertex], key: NodeKey, point: Vector3<f64>) -> Option<SurfacePoint> {
+    let (x, y, z) = key.center();
+    let center = Vector3::new(x, y, z);
+    let size = key.size();
+    let world = |vertex: &Vertex| {
+        let [x, y, z] = vertex.position;
+        center + Vector3::new(x as f64, y as f64, z as f64) * size
+    };
+    let normal = |vertex: &Vertex| {
+        let [x, y, z] = vertex.normal;
+        Vector3::new(x as f64, y as f64, z as f64)
+    };
+
+    let mut best: Option<SurfacePoint> = None;
+    for triangle in vertices.chunks_exact(3) {
+        let (a, b, c) = (world(&triangle[0]), world(&triangle[1]), world(&triangle[2]));
+        let (closest, u, v) = closest_on_triangle(point, a, b, c);
+        let offset = point - closest;
+        let distance = offset.magnitude();
+        if best.is_some_and(|best| distance >= best.distance.abs()) {
+            continue;
+        }
+
+        let mut surface_normal = normal(&triangle[0]) * (1.0 - u - v) + normal(&triangle[1]) * u + normal(&triangle[2]) * v;
+        if surface_normal.magnitude2() == 0.0 {
+            surface_normal = (b - a).cross(c - a);
+        }
+        let surface_normal = surface_normal.normalize();
+        let inside = offset.dot(surface_normal) < 0.0;
+        best = Some(SurfacePoint {
+            point: closest,
+            normal: surface_normal,
+            distance: if inside { -distance } else { distance },
+            node: key,
+        });
+    }
+    best
+}
+
+/// Closest point to `p` on the triangle `abc`, with the barycentric weights
+/// of `b` and `c` there.
+fn closest_on_triangle(p: Vector3<f64>, a: Vector3<f64>, b: Vector3<f64>, c: Vector3<f64>) -> (Vector3<f64>, f64, f64) {
+    // From Ericson, Real-Time Collision Detection, 5.1.5
+    let (ab, ac, ap) = (b - a, c - a, p - a);
+    let (d1, d2) = (ab.dot(ap), ac.dot(ap));
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return (a, 0.0, 0.0);
+    }
+    let bp = p - b;
+    let (d3, d4) = (ab.dot(bp), ac.dot(bp));
+    if d3 >= 0.0 && d4 <= d3 {
+        return (b, 1.0, 0.0);
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let v = d1 / (d1 - d3);
+        return (a + ab * v, v, 0.0);
+    }
+    let cp = p - c;
+    let (d5, d6) = (ab.dot(cp), ac.dot(cp));
+    if d6 >= 0.0 && d5 <= d6 {
+        return (c, 0.0, 1.0);
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let w = d2 / (d2 - d6);
+        return (a + ac * w, 0.0, w);
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return (b + (c - b) * w, 1.0 - w, w);
+    }
+    let denominator = 1.0 / (va + vb + vc);
+    let (v, w) = (vb * denominator, vc * denominator);
+    (a + ab * v + ac * w, v, w)
+}
+
+/// Projects `point` onto the field's surface with Newton steps along the
+/// gradient. The node of the result is left for the caller to fill in.
+fn project(field: &ScalarField, point: Vector3<f64>, epsilon: f64) -> Option<SurfacePoint> {
+    let inside = field(point.x, point.y, point.z) < 0.0;
+    let mut surface = point;
+    for _ in 0..PROJECTION_STEPS {
+        let value = field(surface.x, surface.y, surface.z);
+        let slope = slope(field, surface, epsilon);
+        if slope.magnitude2() == 0.0 {
+            return None;
+        }
+        let step = slope * (value / slope.magnitude2());
+        surface -= step;
+        if step.magnitude() <= epsilon * 1e-3 {
+            break;
+        }
+    }
+
+    let distance = (point - surface).magnitude();
+    Some(SurfacePoint {
+        point: surface,
+        normal: gradient(field, surface, epsilon),
+        distance: if inside { -distance } else { distance },
+        node: NodeKey::root(),
+    })
+}
+
+struct Ray<'a> {
+    origin: Vector3<f64>,
+    /// Unit length.
+    dir: Vector3<f64>,
+    max_dist: f64,
+    field: Option<&'a ScalarField>,
+}
+
+impl<'a> Ray<'a> {
+    #[inline]
+    fn at(&self, t: f64) -> Vector3<f64> {
+        self.origin + self.dir * t
+    }
+
+    /// The part of the ray, as distances along it, inside the node `key`.
+    fn interval(&self, key: NodeKey) -> Option<(f64, f64)> {
+        let (x, y, z) = key.center();
+        let center = Vector3::new(x, y, z);
+        let half = 0.5 * key.size();
+        let mut near = 0.0f64;
+        let mut far = self.max_dist;
+        for axis in 0..3 {
+            let (origin, dir, center) = (self.origin[axis], self.dir[axis], center[axis]);
+            if dir == 0.0 {
+                if (origin - center).abs() > half {
+                    return None;
+                }
+                continue;
+            }
+            let a = (center - half - origin) / dir;
+            let b = (center + half - origin) / dir;
+            near = near.max(a.min(b));
+            far = far.min(a.max(b));
+        }
+        if near <= far { Some((near, far)) } else { None }
+    }
+
+    fn node<B: MeshBackend>(&self, node: &OctreeNode<B>, key: NodeKey, near: f64, far: f64) -> Option<Hit> {
+        if let Some(ref children) = node.children {
+            let mut order: Vec<(f64, f64, usize)> = (0..8)
+                .filter_map(|index| self.interval(key.child(index)).map(|(near, far)| (near, far, index)))
+                .collect();
+            order.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+            return order.into_iter().find_map(|(near, far, index)| self.node(&children[index], key.child(index), near, far));
+        }
+
+        match node.vertices {
+            Some(ref vertices) => self.mesh(vertices, key, near, far),
+            None => self.march(key, near, far),
+        }
+    }
+
+    /// Nearest triangle of `vertices` hit between `near` and `far`.
+    fn mesh(&self, vertices: &[Vertex], key: NodeKey, near: f64, far: f64) -> Option<Hit> {
+        let (x, y, z) = key.center();
+        let center = Vector3::new(x, y, z);
+        let size = key.size();
+        let world = |vertex: &Vertex| {
+            let [x, y, z] = vertex.position;
+            center + Vector3::new(x as f64, y as f64, z as f64) * size
+        };
+
+        let mut best: Option<Hit> = None;
+        for triangle in vertices.chunks_exact(3) {
+            let (a, b, c) = (world(&triangle[0]), world(&triangle[1]), world(&triangle[2]));
+            // Möller–Trumbore
+            let (ab, ac) = (b - a, c - a);
+            let p = self.dir.cross(ac);
+            let det = ab.dot(p);
+            if det.abs() < 1e-300 {
+                continue;
+            }
+            let s = self.origin - a;
+            let u = s.dot(p) / det;
+            let q = s.cross(ab);
+            let v = self.dir.dot(q) / det;
+            let t = ac.dot(q) / det;
+            if u < 0.0 || v < 0.0 || u + v > 1.0 || t < near || t > far || best.is_some_and(|hit| t >= hit.distance) {
+                continue;
+            }
+
+            let normal = |vertex: &Vertex| {
+                let [x, y, z] = vertex.normal;
+                Vector3::new(x as f64, y as f64, z as f64)
+            };
+            let mut normal = normal(&triangle[0]) * (1.0 - u - v) + normal(&triangle[1]) * u + normal(&triangle[2]) * v;
+            if normal.magnitude2() == 0.0 {
+                normal = -self.dir;
+            }
+            best = Some(Hit { point: self.at(t), normal: normal.normalize(), distance: t, node: key });
+        }
+        best
+    }
+
+    /// Steps through the field between `near` and `far` and bisects the
+    /// first step crossing from outside to inside.
+    fn march(&self, key: NodeKey, near: f64, far: f64) -> Option<Hit> {
+        let field = self.field?;
+        let sample = |t: f64| {
+            let point = self.at(t);
+            field(point.x, point.y, point.z)
+        };
+        let hit = |t: f64| {
+            let point = self.at(t);
+            Hit { point, normal: gradient(field, point, key.size() * 1e-3), distance: t, node: key }
+        };
+
+        let step = key.size() / FIELD_STEPS;
+        let mut previous = (near, sample(near));
+        if previous.1 < 0.0 {
+            return Some(hit(near));
+        }
+        while previous.0 < far {
+            let t = (previous.0 + step).min(far);
+            let value = sample(t);
+            if value < 0.0 {
+                let (mut outside, mut inside) = (previous.0, t);
+                for _ in 0..BISECTIONS {
+                    let middle = 0.5 * (outside + inside);
+                    if sample(middle) < 0.0 {
+                        inside = middle;
+                    } else {
+                        outside = middle;
+                    }
+                }
+                return Some(hit(0.5 * (outside + inside)));
+            }
+            previous = (t, value);
+        }
+        None
+    }
+}
+
+/// Unit gradient of `field` at `point` by central differences over `epsilon`,
+/// pointing out of the surface.
+pub(crate) fn gradient(field: &ScalarField, point: Vector3<f64>, epsilon: f64) -> Vector3<f64> {
+    let gradient = slope(field, point, epsilon);
+    if gradient.magnitude2() > 0.0 { gradient.normalize() } else { gradient }
+}
+
+/// Derivative of `field` at `point` by central differences over `epsilon`.
+fn slope(field: &ScalarField, point: Vector3<f64>, epsilon: f64) -> Vector3<f64> {
+    let (x, y, z) = (point.x, point.y, point.z);
+    Vector3::new(
+        field(x + epsilon, y, z) - field(x - epsilon, y, z),
+        field(x, y + epsilon, z) - field(x, y - epsilon, z),
+        field(x, y, z + epsilon) - field(x, y, z - epsilon),
+    ) / (2.0 * epsilon)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RecordingBackend;
+    use crate::worker::Worker;
+
+    fn sphere(x: f64, y: f64, z: f64) -> f64 {
+        x.powi(2) + y.powi(2) + z.powi(2) - 0.2
+    }
+
+    #[test]
+    fn raycast() {
+        let mut octree = Octree::with_worker(RecordingBackend::new(), Worker::synchronous(sphere));
+        let radius = 0.2f64.sqrt();
+
+        let hit = octree.raycast(Vector3::new(-2.0, 0.0, 0.0), Vector3::new(3.0, 0.0, 0.0), 10.0).unwrap();
+        assert!((hit.distance - (2.0 - radius)).abs() < 1e-9);
+        assert!((hit.point.x + radius).abs() < 1e-9);
+        assert!((hit.normal - Vector3::new(-1.0, 0.0, 0.0)).magnitude() < 1e-6);
+        assert!(hit.node == NodeKey::root());
+
+        assert!(octree.raycast(Vector3::new(-2.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0), 10.0).is_none());
+        assert!(octree.raycast(Vector3::new(-2.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0), 1.0).is_none());
+        let inside = octree.raycast(Vector3::new(0.1, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0), 1.0).unwrap();
+        assert!(inside.distance == 0.0);
+        assert!(octree.raycast(Vector3::new(0.1, 0.0, 0.0), Vector3::new(0.0, 0.0, 0.0), 1.0).is_none());
+
+        // With retained meshes the hit is on the deepest node's triangles,
+        // close to the field's surface
+        octree.set_retain_vertices(true);
+        octree.walk(&|node, info, key, level, x, y, z| {
+            node.create_children(info, key, level, x, y, z);
+        });
+        octree.update();
+        let origin = Vector3::new(0.2, 0.2, 2.0);
+        let hit = octree.raycast(origin, Vector3::new(0.0, 0.0, -1.0), 10.0).unwrap();
+        let expected = 2.0 - (0.2f64 - 0.2 * 0.2 - 0.2 * 0.2).sqrt();
+        assert!(hit.node.level == 1);
+        assert!((hit.distance - expected).abs() < 0.02);
+        assert!(hit.normal.z > 0.0);
+    }
+
+    #[test]
+    fn collision() {
+        let mut octree = Octree::with_worker(RecordingBackend::new(), Worker::synchronous(sphere));
+        let radius = 0.2f64.sqrt();
+
+        let closest = octree.closest_point(Vector3::new(0.0, 0.6, 0.0), 1.0).unwrap();
+        assert!((closest.point - Vector3::new(0.0, radius, 0.0)).magnitude() < 1e-6);
+        assert!((closest.distance - (0.6 - radius)).abs() < 1e-6);
+        assert!((closest.normal - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1e-6);
+        assert!(octree.closest_point(Vector3::new(0.0, 0.6, 0.0), 0.1).is_none());
+        assert!(octree.closest_point(Vector3::new(0.0, 0.4, 0.0), 0.1).unwrap().distance < 0.0);
+
+        let contact = octree.sphere_contact(Vector3::new(0.0, 0.0, 0.5), 0.1).unwrap();
+        assert!((contact.depth - (0.1 - (0.5 - radius))).abs() < 1e-6);
+        assert!(contact.normal.z > 0.99);
+        assert!(!octree.overlaps_sphere(Vector3::new(0.0, 0.0, 0.6), 0.1));
+
+        // A capsule lying above the sphere touches it in the middle only
+        let (a, b) = (Vector3::new(-0.3, 0.5, 0.0), Vector3::new(0.3, 0.5, 0.0));
+        let contact = octree.capsule_contact(a, b, 0.1).unwrap();
+        assert!(contact.point.x.abs() < 1e-3);
+        assert!((contact.depth - (0.1 - (0.5 - radius))).abs() < 1e-4);
+        assert!(!octree.overlaps_capsule(a + Vector3::new(0.0, 0.1, 0.0), b + Vector3::new(0.0, 0.1, 0.0), 0.1));
+
+        // Against retained meshes, at level 1
+        octree.set_retain_vertices(true);
+        octree.walk(&|node, info, key, level, x, y, z| {
+            node.create_children(info, key, level, x, y, z);
+        });
+        octree.update();
+        let contact = octree.sphere_contact(Vector3::new(0.0, 0.0, 0.5), 0.1).unwrap();
+        assert!(contact.node.level == 1);
+        assert!((contact.depth - (0.1 - (0.5 - radius))).abs() < 0.02);
+        assert!(contact.normal.z > 0.9);
+    }
+}
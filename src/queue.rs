@@ -0,0 +1,331 @@
+#![allow(dead_code)]
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::Arc;
+use crate::octree::{self, NodeKey};
+use crate::worker::Task;
+
+/// Computes a node's priority from its level and centre. Lower values are
+/// generated first.
+pub type Metric = Arc<dyn Fn(i32, f64, f64, f64) -> f64 + Send + Sync>;
+
+/// Shallowest nodes first, the order used before metrics were configurable.
+pub fn by_level() -> Metric {
+    Arc::new(|level, _x, _y, _z| f64::from(level))
+}
+
+/// Nodes that look biggest from `(x, y, z)` first: distance to the node
+/// divided by its size, a cheap stand-in for screen-space error.
+pub fn by_distance(x: f64, y: f64, z: f64) -> Metric {
+    Arc::new(move |level, node_x, node_y, node_z| {
+        let size = octree::level_size(level);
+        let distance = ((node_x - x).powi(2) + (node_y - y).powi(2) + (node_z - z).powi(2)).sqrt();
+        distance / size
+    })
+}
+
+struct Entry {
+    priority: f64,
+    sequence: u64,
+    task: Task,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    // `BinaryHeap` pops the greatest entry, so the lowest priority value (and,
+    // among equals, the oldest task) has to compare as the greatest.
+    fn cmp(&self, other: &Entry) -> Ordering {
+        other.priority.partial_cmp(&self.priority).unwrap_or(Ordering::Equal)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// Pending generation tasks ordered by a caller-supplied metric, holding at
+/// most one mesh task per source and node key. Detached tasks (jobs and mesh
+/// requests with a handle) are kept apart from that rule.
+///
+/// Each source (a worker sharing the pool) may set a metric of its own;
+/// the others use the default one. All priorities share one heap.
+///
+/// Replaced and removed tasks stay in the heap until popped, or until they
+/// outnumber the live ones and the heap is rebuilt; `live` maps each key to
+/// the sequence number and epoch of its current entry.
+pub struct TaskQueue {
+    heap: BinaryHeap<Entry>,
+    live: HashMap<(usize, NodeKey), (u64, u64)>,
+    jobs: usize,
+    metric: Metric,
+    source_metrics: HashMap<usize, Metric>,
+    sequence: u64,
+}
+
+impl TaskQueue {
+    pub fn new() -> TaskQueue {
+        TaskQueue {
+            heap: BinaryHeap::with_capacity(100),
+            live: HashMap::new(),
+            jobs: 0,
+            metric: by_level(),
+            source_metrics: HashMap::new(),
+            sequence: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.live.len() + self.jobs
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, source: usize, key: NodeKey) -> bool {
+        self.live.contains_key(&(source, key))
+    }
+
+    /// Queues `task`, replacing any mesh task already queued for the same
+    /// source and key. Returns true if one was replaced.
+    pub fn push(&mut self, task: Task) -> bool {
+        let priority = self.priority(&task);
+        self.sequence += 1;
+        let replaced = if task.is_detached() {
+            self.jobs += 1;
+            false
+        } else {
+            self.live.insert((task.source, task.key), (self.sequence, task.epoch)).is_some()
+        };
+        self.heap.push(Entry { priority, sequence: self.sequence, task });
+        if replaced {
+            self.compact();
+        }
+        replaced
+    }
+
+    pub fn pop(&mut self) -> Option<Task> {
+        while let Some(entry) = self.heap.pop() {
+            if entry.task.is_detached() {
+                self.jobs -= 1;
+                return Some(entry.task);
+            }
+            if self.is_live(&entry) {
+                self.live.remove(&(entry.task.source, entry.task.key));
+                return Some(entry.task);
+            }
+        }
+        None
+    }
+
+    /// Removes the task queued by `source` for `key` if it belongs to `epoch`.
+    pub fn remove(&mut self, source: usize, key: NodeKey, epoch: u64) -> bool {
+        match self.live.get(&(source, key)) {
+            Some(&(_, live_epoch)) if live_epoch == epoch => {
+                self.live.remove(&(source, key));
+                self.compact();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drops every task queued by `source`, along with its metric.
+    pub fn remove_source(&mut self, source: usize) -> usize {
+        self.source_metrics.remove(&source);
+        self.retain(|task| task.source != source)
+    }
+
+    pub fn metric(&self) -> Metric {
+        self.metric.clone()
+    }
+
+    /// Replaces the default metric and re-prioritizes every pending task.
+    pub fn set_metric(&mut self, metric: Metric) {
+        self.metric = metric;
+        self.reprioritize();
+    }
+
+    /// Sets the metric for `source`'s tasks and re-prioritizes every pending
+    /// task.
+    pub fn set_source_metric(&mut self, source: usize, metric: Metric) {
+        self.source_metrics.insert(source, metric);
+        self.reprioritize();
+    }
+
+    /// Recomputes every pending task's priority, for metrics that read state
+    /// (e.g. a shared camera position) which has changed since they were queued.
+    pub fn reprioritize(&mut self) {
+        let mut entries = self.take_live();
+        for entry in entries.iter_mut() {
+            entry.priority = self.priority(&entry.task);
+        }
+        self.heap = BinaryHeap::from(entries);
+    }
+
+    #[inline]
+    fn priority(&self, task: &Task) -> f64 {
+        let metric = self.source_metrics.get(&task.source).unwrap_or(&self.metric);
+        metric(task.level, task.x, task.y, task.z)
+    }
+
+    /// Keeps only the tasks for which `keep` returns true.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Task) -> bool) -> usize {
+        let before = self.len();
+        let entries = self.take_live();
+        self.heap = entries.into_iter().filter(|entry| keep(&entry.task)).collect();
+        let live = self.heap.iter()
+            .filter(|entry| !entry.task.is_detached())
+            .map(|entry| ((entry.task.source, entry.task.key), (entry.sequence, entry.task.epoch)))
+            .collect();
+        self.live = live;
+        self.jobs = self.heap.iter().filter(|entry| entry.task.is_detached()).count();
+        before - self.len()
+    }
+
+    #[inline]
+    fn is_live(&self, entry: &Entry) -> bool {
+        entry.task.is_detached() ||
+            self.live.get(&(entry.task.source, entry.task.key)).map(|&(sequence, _)| sequence) == Some(entry.sequence)
+    }
+
+    /// Rebuilds the heap without its dead entries once they outnumber the
+    /// live ones, so it stays within twice the queue's length.
+    fn compact(&mut self) {
+        if self.heap.len() > 2 * self.len() {
+            self.heap = BinaryHeap::from(self.take_live());
+        }
+    }
+
+    /// Empties the heap, returning only the entries that are still live.
+    fn take_live(&mut self) -> Vec<Entry> {
+        let entries = std::mem::take(&mut self.heap).into_vec();
+        entries.into_iter().filter(|entry| self.is_live(entry)).collect()
+    }
+}
+
+impl Default for TaskQueue {
+    fn default() -> TaskQueue {
+        TaskQueue::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(level: i32, x: f64) -> Task {
+        // Unique key per (level, x) so tasks don't coalesce
+        let key = NodeKey { level, x: (x * 1000.0) as i64, y: 0, z: 0 };
+        Task { x, y: 0.0, z: 0.0, ..Task::generate(key, 0) }
+    }
+
+    #[test]
+    fn level_order() {
+        let mut queue = TaskQueue::new();
+        queue.push(task(2, 0.0));
+        queue.push(task(0, 0.0));
+        queue.push(task(1, 0.0));
+
+        assert!(queue.pop().unwrap().level == 0);
+        assert!(queue.pop().unwrap().level == 1);
+        assert!(queue.pop().unwrap().level == 2);
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn fifo_among_equals() {
+        let mut queue = TaskQueue::new();
+        queue.push(task(1, 0.1));
+        queue.push(task(1, 0.2));
+
+        assert!(queue.pop().unwrap().x == 0.1);
+        assert!(queue.pop().unwrap().x == 0.2);
+    }
+
+    #[test]
+    fn reprioritize() {
+        let mut queue = TaskQueue::new();
+        queue.push(task(1, -0.25));
+        queue.push(task(1, 0.25));
+
+        queue.set_metric(by_distance(0.3, 0.0, 0.0));
+        assert!(queue.pop().unwrap().x == 0.25);
+
+        queue.push(task(1, 0.25));
+        queue.set_metric(by_distance(-0.3, 0.0, 0.0));
+        assert!(queue.pop().unwrap().x == -0.25);
+    }
+
+    #[test]
+    fn coalesce() {
+        let mut queue = TaskQueue::new();
+        assert!(!queue.push(task(1, 0.25)));
+        let mut newer = task(1, 0.25);
+        newer.epoch = 1;
+        assert!(queue.push(newer));
+        assert!(queue.len() == 1);
+
+        assert!(!queue.remove(0, task(1, 0.25).key, 0));
+        assert!(queue.pop().unwrap().epoch == 1);
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn sources() {
+        let mut queue = TaskQueue::new();
+        queue.push(task(1, 0.25));
+        queue.push(Task { source: 1, ..task(1, 0.25) });
+        assert!(queue.len() == 2);
+
+        queue.set_source_metric(1, Arc::new(|_level, _x, _y, _z| -1.0));
+        assert!(queue.pop().unwrap().source == 1);
+
+        queue.push(Task { source: 1, ..task(2, 0.25) });
+        assert!(queue.remove_source(1) == 1);
+        assert!(queue.pop().unwrap().source == 0);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn remove() {
+        let mut queue = TaskQueue::new();
+        queue.push(task(1, 0.25));
+        queue.push(task(2, 0.25));
+        assert!(queue.remove(0, task(1, 0.25).key, 0));
+        assert!(queue.len() == 1);
+        assert!(queue.pop().unwrap().level == 2);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn bounded_heap() {
+        let mut queue = TaskQueue::new();
+        queue.push(task(2, 0.5));
+        for epoch in 0..1000 {
+            queue.push(Task { epoch, ..task(1, 0.25) });
+        }
+        assert!(queue.len() == 2);
+        assert!(queue.heap.len() <= 4);
+
+        for epoch in 0..1000 {
+            queue.push(Task { epoch, ..task(3, 0.75) });
+            assert!(queue.remove(0, task(3, 0.75).key, epoch));
+        }
+        assert!(queue.heap.len() <= 4);
+        assert!(queue.pop().unwrap().epoch == 999);
+        assert!(queue.pop().unwrap().level == 2);
+        assert!(queue.pop().is_none());
+    }
+}
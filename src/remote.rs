@@ -0,0 +1,295 @@
+#![allow(dead_code)]
+
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use crate::archive::{read_i64, read_u32, read_vertex, write_i64, write_u32, write_vertex};
+use crate::error::Error;
+use crate::geometry::Vertex;
+use crate::isosurface;
+use crate::job::{self, JobContext, MeshJob, ScalarField};
+use crate::material::MaterialField;
+use crate::octree::{NodeKey, MAX_LEVEL};
+
+const MAGIC: &[u8; 4] = b"UVWK";
+const VERSION: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_PANIC: u8 = 1;
+/// The request named a node that doesn't exist.
+const STATUS_INVALID: u8 = 2;
+
+/// Longest panic message accepted from a worker process.
+const MAX_MESSAGE: usize = 64 * 1024;
+
+/// Serves mesh requests on `listener`, one thread per connection, until
+/// accepting fails. Meshes blend materials from `material_field`, if given,
+/// like those of `Pool::worker_with_materials`.
+///
+/// Protocol (little endian): both sides send magic and version, then the
+/// client sends `level, x, y, z` keys and reads back a status byte followed
+/// by the vertices or, if generation panicked or the key was invalid, a
+/// message.
+pub fn serve(listener: UnixListener, scalar_field: Arc<ScalarField>, material_field: Option<Arc<MaterialField>>) -> Result<(), Error> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let scalar_field = scalar_field.clone();
+        let material_field = material_field.clone();
+        thread::Builder::new()
+            .name(String::from("mesher-connection"))
+            .spawn(move || {
+                if let Err(err) = handle(stream, &*scalar_field, material_field.as_deref()) {
+                    eprintln!("Connection closed: {}", err);
+                }
+            })?;
+    }
+    Ok(())
+}
+
+/// Answers requests from one client until it disconnects.
+fn handle(stream: UnixStream, scalar_field: &ScalarField, material_field: Option<&MaterialField>) -> Result<(), Error> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    handshake(&mut reader, &mut writer)?;
+
+    loop {
+        let level = match read_u32(&mut reader) {
+            Ok(level) => level as i32,
+            Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(Error::from(err)),
+        };
+        let key = NodeKey {
+            level,
+            x: read_i64(&mut reader)?,
+            y: read_i64(&mut reader)?,
+            z: read_i64(&mut reader)?,
+        };
+        if !(0..=MAX_LEVEL).contains(&level) {
+            write_message(&mut writer, STATUS_INVALID, format!("no nodes at level {}", level))?;
+            writer.flush()?;
+            continue;
+        }
+
+        let context = JobContext::new(key, scalar_field).with_materials(material_field);
+        match job::run_caught(&MeshJob, &context) {
+            Ok(data) => {
+                writer.write_all(&[STATUS_OK])?;
+                write_u32(&mut writer, data.len() as u32)?;
+                for vertex in &data {
+                    write_vertex(&mut writer, vertex)?;
+                }
+            }
+            Err(err) => {
+                let message = match err {
+                    Error::Panic(_, message) => message,
+                    err => err.to_string(),
+                };
+                write_message(&mut writer, STATUS_PANIC, message)?;
+            }
+        }
+        writer.flush()?;
+    }
+}
+
+/// Writes `status` followed by `message`, cut to `MAX_MESSAGE` bytes.
+fn write_message(writer: &mut impl Write, status: u8, mut message: String) -> Result<(), Error> {
+    if message.len() > MAX_MESSAGE {
+        let mut end = MAX_MESSAGE;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+    writer.write_all(&[status])?;
+    write_u32(writer, message.len() as u32)?;
+    writer.write_all(message.as_bytes())?;
+    Ok(())
+}
+
+fn handshake(reader: &mut impl Read, writer: &mut impl Write) -> Result<(), Error> {
+    writer.write_all(MAGIC)?;
+    write_u32(writer, VERSION)?;
+    writer.flush()?;
+
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::Remote(String::from("peer is not a universe worker")));
+    }
+    let version = read_u32(reader)?;
+    if version != VERSION {
+        return Err(Error::Remote(format!("unsupported protocol version {}", version)));
+    }
+    Ok(())
+}
+
+struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: BufWriter<UnixStream>,
+}
+
+impl Connection {
+    fn open(path: &Path) -> Result<Connection, Error> {
+        let stream = UnixStream::connect(path)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        handshake(&mut reader, &mut writer)?;
+        Ok(Connection { reader, writer })
+    }
+
+    /// Sends a request and reads the response. Errors the worker process
+    /// answered with come back inside `Ok`, as the connection is still in
+    /// sync after them.
+    fn generate(&mut self, key: NodeKey) -> Result<Result<Vec<Vertex>, Error>, Error> {
+        write_u32(&mut self.writer, key.level as u32)?;
+        write_i64(&mut self.writer, key.x)?;
+        write_i64(&mut self.writer, key.y)?;
+        write_i64(&mut self.writer, key.z)?;
+        self.writer.flush()?;
+
+        let mut status = [0u8; 1];
+        self.reader.read_exact(&mut status)?;
+        let len = read_u32(&mut self.reader)? as usize;
+        match status[0] {
+            STATUS_OK if len > isosurface::MAX_VERTICES => Err(Error::Remote(format!("response of {} vertices is too long", len))),
+            STATUS_PANIC | STATUS_INVALID if len > MAX_MESSAGE => Err(Error::Remote(format!("message of {} bytes is too long", len))),
+            STATUS_OK => {
+                let mut data = Vec::<Vertex>::with_capacity(len);
+                for _ in 0..len {
+                    data.push(read_vertex(&mut self.reader)?);
+                }
+                Ok(Ok(data))
+            }
+            STATUS_PANIC | STATUS_INVALID => {
+                let mut message = vec![0u8; len];
+                self.reader.read_exact(&mut message)?;
+                let message = String::from_utf8_lossy(&message).into_owned();
+                if status[0] == STATUS_PANIC {
+                    Ok(Err(Error::Panic(key, message)))
+                } else {
+                    Ok(Err(Error::Remote(message)))
+                }
+            }
+            status => Err(Error::Remote(format!("unknown response status {}", status))),
+        }
+    }
+}
+
+/// Requests meshes from a worker process listening on a Unix socket.
+///
+/// A failed connection is dropped and reopened on the next request, so a
+/// restarted worker process is picked up again.
+pub struct Client {
+    path: PathBuf,
+    connection: Option<Connection>,
+}
+
+impl Client {
+    /// Connects to `path`, failing if no worker is listening there.
+    pub fn connect(path: &Path) -> Result<Client, Error> {
+        let connection = Connection::open(path)?;
+        Ok(Client { path: path.to_path_buf(), connection: Some(connection) })
+    }
+
+    /// Generates the mesh for `key`. Panics in the worker process come back
+    /// as `Error::Panic` and keys it refuses as `Error::Remote`; anything
+    /// else as the error that broke the connection.
+    pub fn generate(&mut self, key: NodeKey) -> Result<Vec<Vertex>, Error> {
+        let connection = match self.connection {
+            Some(ref mut connection) => connection,
+            None => self.connection.get_or_insert(Connection::open(&self.path)?),
+        };
+        match connection.generate(key) {
+            Ok(result) => result,
+            Err(err) => {
+                self.connection = None;
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::job::Job;
+    use crate::material::{self, Material};
+    use crate::worker::Worker;
+
+    #[test]
+    fn matches_local() {
+        let path = std::env::temp_dir().join(format!("universe-remote-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (scalar_field, material_field) = material::split(|x, y, z| {
+            (x * x + y * y + z * z - 0.2, if y > 0.0 { Material::Snow } else { Material::Rock })
+        });
+        let (served, served_materials) = (scalar_field.clone(), material_field.clone());
+        thread::spawn(move || serve(listener, served, Some(served_materials)));
+
+        let mut client = Client::connect(&path).unwrap();
+        for &key in &[NodeKey::root(), NodeKey::root().child(3)] {
+            let remote = client.generate(key).unwrap();
+            let local = MeshJob.run(&JobContext::new(key, &*scalar_field).with_materials(Some(&*material_field)));
+            assert!(!remote.is_empty());
+            assert!(remote.len() == local.len());
+            assert!(remote.iter().zip(&local).all(|(a, b)| a.position == b.position && a.normal == b.normal && a.material == b.material));
+            assert!(remote.iter().any(|vertex| vertex.material == Material::Snow.weights()));
+        }
+
+        // One unreachable socket fails the whole worker
+        let missing = path.with_extension("missing");
+        assert!(Worker::remote(&[path.clone(), missing]).is_err());
+        assert!(Worker::remote(&[path.clone()]).is_ok());
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let (client, server) = UnixStream::pair().unwrap();
+        thread::spawn(move || handle(server, &|x, y, z| x * x + y * y + z * z - 0.2, None));
+        let mut connection = Connection { reader: BufReader::new(client.try_clone().unwrap()), writer: BufWriter::new(client) };
+        handshake(&mut connection.reader, &mut connection.writer).unwrap();
+
+        // Refused without dropping the connection
+        for &level in &[-1, MAX_LEVEL + 1] {
+            match connection.generate(NodeKey { level, ..NodeKey::root() }) {
+                Ok(Err(Error::Remote(_))) => {}
+                _ => panic!("Expected the key to be refused"),
+            }
+        }
+        assert!(matches!(connection.generate(NodeKey::root()), Ok(Ok(_))));
+    }
+
+    #[test]
+    fn lossy_panic_message() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let mut connection = Connection { reader: BufReader::new(client.try_clone().unwrap()), writer: BufWriter::new(client) };
+        server.write_all(&[STATUS_PANIC]).unwrap();
+        write_u32(&mut server, 2).unwrap();
+        server.write_all(&[b'a', 0xff]).unwrap();
+        match connection.generate(NodeKey::root()) {
+            Ok(Err(Error::Panic(_, message))) => assert!(message == "a\u{fffd}"),
+            _ => panic!("Expected the panic to come back"),
+        }
+    }
+
+    #[test]
+    fn oversized_response() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let mut connection = Connection { reader: BufReader::new(client.try_clone().unwrap()), writer: BufWriter::new(client) };
+        server.write_all(&[STATUS_OK]).unwrap();
+        write_u32(&mut server, u32::MAX).unwrap();
+        match connection.generate(NodeKey::root()) {
+            Err(Error::Remote(_)) => {}
+            _ => panic!("Expected the response to be refused"),
+        }
+    }
+}
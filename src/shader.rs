@@ -12,6 +12,8 @@ pub enum Attribute {
     Position = 0,
     Normal = 1,
     UV = 2,
+    Material = 3,
+    Tangent = 4,
 }
 
 pub enum Uniform {
@@ -41,6 +43,8 @@ impl Shader {
         writeln!(&mut src, "#define ATTRIB_POSITION {}", Attribute::Position as GLuint)?;
         writeln!(&mut src, "#define ATTRIB_NORMAL {}", Attribute::Normal as GLuint)?;
         writeln!(&mut src, "#define ATTRIB_UV {}", Attribute::UV as GLuint)?;
+        writeln!(&mut src, "#define ATTRIB_MATERIAL {}", Attribute::Material as GLuint)?;
+        writeln!(&mut src, "#define ATTRIB_TANGENT {}", Attribute::Tangent as GLuint)?;
         writeln!(&mut src, "#define UNIFORM_MODEL_VIEW {}", Uniform::ModelView as GLuint)?;
         writeln!(&mut src, "#define UNIFORM_PROJECTION {}", Uniform::Projection as GLuint)?;
 
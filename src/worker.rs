@@ -1,18 +1,160 @@
 #![allow(dead_code)]
 
-use std::sync::mpsc::{channel, Sender, Receiver, TryIter};
-use std::thread;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::path::Path;
+use crate::error::Error;
 use crate::geometry::Vertex;
-use crate::isosurface::Isosurface;
+use crate::job::{self, ChunkHandle, Job, JobContext, JobHandle, MeshJob, ErasedJob, Promise, ScalarField};
+use crate::material::{self, Material, MaterialField};
+use crate::metrics::{Timing, WorkerMetrics};
+use crate::octree::NodeKey;
+use crate::queue::{Metric, TaskQueue};
+#[cfg(unix)]
+use crate::remote::Client;
 
+/// Turns a node of the field into its mesh. `MeshJob` is the default; any
+/// job producing vertices can stand in for it.
+pub type Mesher = dyn Job<Output = Vec<Vertex>> + Sync;
+
+/// Mesher threads shared by several `Worker`s, e.g. one per planet.
+///
+/// Each worker brings its own field, mesher and result channel, while every
+/// task goes through one queue ordered by each worker's priority metric. For
+/// that order to be global, the metrics have to agree on a scale, e.g.
+/// `queue::by_distance` from the camera expressed in each body's own space.
+///
+/// The threads stop once the pool and every worker made from it are dropped.
+#[derive(Clone)]
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+/// A client of a `Pool`: sends it tasks for one field and receives their
+/// results. Workers built with `Worker::spawn` and friends own a pool of
+/// their own.
 pub struct Worker {
-    tasks: Sender<Task>,
+    shared: Arc<Shared>,
+    source: Arc<Source>,
     results: Receiver<Result>,
 }
 
-struct Parent {
-    tasks: Receiver<Task>,
-    results: Sender<Result>,
+/// Owns the pool's threads, joining them when the last handle goes away.
+struct Shared {
+    queue: Arc<Queue>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    /// Set for synchronous pools, which generate on the caller's thread.
+    synchronous: bool,
+}
+
+/// What a worker's tasks are generated with and where their results go.
+struct Source {
+    id: usize,
+    /// Unset for remote workers, which generate in another process.
+    generator: Option<Generator>,
+    results: SyncSender<Result>,
+    /// Results sent but not yet received.
+    results_pending: AtomicUsize,
+}
+
+struct Generator {
+    scalar_field: Arc<ScalarField>,
+    material_field: Option<Arc<MaterialField>>,
+    mesher: Arc<Mesher>,
+}
+
+/// Shared by every thread in the pool.
+struct Queue {
+    state: Mutex<State>,
+    /// Signalled when a task is queued.
+    available: Condvar,
+    /// Signalled when a task leaves the queue, for blocked senders.
+    space: Condvar,
+    limits: QueueLimits,
+    started: Instant,
+    /// Whether mesh tasks time their scalar field, see `Pool::set_field_timing`.
+    time_field: AtomicBool,
+}
+
+/// Capacities of the task queue and the result channel. A full task queue
+/// refuses `try_send` and blocks `send`; a full result channel blocks the
+/// mesher threads until results are received.
+///
+/// In a shared `Pool` the task queue is shared, while each worker has a
+/// result channel of its own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueLimits {
+    pub tasks: usize,
+    pub results: usize,
+}
+
+impl Default for QueueLimits {
+    fn default() -> QueueLimits {
+        QueueLimits { tasks: 4096, results: 256 }
+    }
+}
+
+/// Queue depth metrics, from `Worker::queue_metrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueueMetrics {
+    pub pending: usize,
+    pub capacity: usize,
+    /// Deepest the task queue has been.
+    pub high_water: usize,
+    pub in_flight: usize,
+    pub results_pending: usize,
+    pub results_capacity: usize,
+    /// Tasks that replaced an earlier queued task for the same node.
+    pub coalesced: usize,
+    /// `try_send` calls refused because the queue was full.
+    pub rejected: usize,
+}
+
+struct State {
+    tasks: TaskQueue,
+    /// Tasks a thread is currently generating, by source, node key and epoch.
+    in_flight: HashSet<(usize, NodeKey, u64)>,
+    /// In-flight tasks cancelled since they started, whose results are dropped.
+    cancelled: HashSet<(usize, NodeKey, u64)>,
+    sources: HashMap<usize, Arc<Source>>,
+    next_source: usize,
+    stats: CancelStats,
+    high_water: usize,
+    coalesced: usize,
+    rejected: usize,
+    panics: usize,
+    metrics: WorkerMetrics,
+    shutdown: bool,
+}
+
+/// Snapshot of the pool's health, from `Worker::status`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkerStatus {
+    pub threads: usize,
+    /// Threads still running; fewer than `threads` means some have exited.
+    pub alive: usize,
+    pub pending: usize,
+    pub in_flight: usize,
+    /// Tasks whose scalar field or mesher panicked.
+    pub panics: usize,
+}
+
+/// How cancellation requests were resolved.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CancelStats {
+    /// Cancel tasks received.
+    pub requested: usize,
+    /// Tasks removed from the queue before a thread picked them up.
+    pub dequeued: usize,
+    /// Tasks cancelled while being generated, whose results were dropped.
+    pub discarded: usize,
+    /// Results that arrived after their node was destroyed or recreated.
+    /// Only counted by `Octree::cancel_stats`.
+    pub stale: usize,
 }
 
 #[derive(PartialEq)]
@@ -23,95 +165,916 @@ pub enum TaskAction {
 
 pub struct Task {
     pub action: TaskAction,
+    pub key: NodeKey,
+    pub epoch: u64,
     pub x: f64,
     pub y: f64,
     pub z: f64,
     pub level: i32,
-    pub path: Vec<i8>,
+    /// The worker that sent the task, set when it's accepted.
+    pub(crate) source: usize,
+    /// Runs instead of mesh generation, for tasks from `Worker::submit`.
+    /// These never coalesce and aren't cancelled by key.
+    pub(crate) job: Option<Box<dyn ErasedJob>>,
+    /// Receives the mesh instead of the result channel, for tasks from
+    /// `Worker::request`. Like jobs, these are detached from coalescing and
+    /// cancellation by key.
+    pub(crate) reply: Option<Promise<Vec<Vertex>>>,
+    /// When the worker accepted the task.
+    pub(crate) queued_at: Instant,
+}
+
+impl Task {
+    pub fn generate(key: NodeKey, epoch: u64) -> Task {
+        let (x, y, z) = key.center();
+        Task {
+            action: TaskAction::Generate,
+            key,
+            epoch,
+            x,
+            y,
+            z,
+            level: key.level,
+            source: 0,
+            job: None,
+            reply: None,
+            queued_at: Instant::now(),
+        }
+    }
+
+    pub fn cancel(key: NodeKey, epoch: u64) -> Task {
+        Task { action: TaskAction::Cancel, ..Task::generate(key, epoch) }
+    }
+
+    /// Whether the task delivers its own output rather than a `Result`.
+    #[inline]
+    pub(crate) fn is_detached(&self) -> bool {
+        self.job.is_some() || self.reply.is_some()
+    }
 }
 
 pub struct Result {
-    pub path: Vec<i8>,
-    pub data: Vec<Vertex>,
+    pub key: NodeKey,
+    pub epoch: u64,
+    pub level: i32,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    /// The mesh, or `Error::Panic` if generating it panicked. Remote workers
+    /// also report the error that broke their connection here.
+    pub data: std::result::Result<Vec<Vertex>, Error>,
 }
 
-impl Worker {
-    pub fn spawn(scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + 'static) -> Worker {
-        let (sender_task, receiver_task) = channel::<Task>();
-        let (sender_result, receiver_result) = channel::<Result>();
+impl Pool {
+    /// Spawns one mesher thread per available core.
+    pub fn spawn() -> Pool {
+        let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        Pool::with_threads(threads)
+    }
+
+    pub fn with_threads(threads: usize) -> Pool {
+        Pool::with_limits(threads, QueueLimits::default())
+    }
+
+    pub fn with_limits(threads: usize, limits: QueueLimits) -> Pool {
+        let queue = Pool::queue(limits);
+        let threads = (0..threads.max(1)).map(|index| {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("mesher-{}", index))
+                .spawn(move || Pool::run(&queue, |task, source| Pool::execute(&queue, source, task)))
+                .expect("Can't spawn mesher thread")
+        }).collect();
+
+        Pool {
+            shared: Arc::new(Shared { queue, threads: Mutex::new(threads), synchronous: false }),
+        }
+    }
+
+    /// A pool without threads: tasks only run when one of its workers'
+    /// `pump` is called, on the calling thread, in priority order. Identical
+    /// tasks sent in the same order always produce identical results in the
+    /// same order.
+    pub fn synchronous() -> Pool {
+        Pool::synchronous_with_limits(QueueLimits::default())
+    }
+
+    pub fn synchronous_with_limits(limits: QueueLimits) -> Pool {
+        Pool {
+            shared: Arc::new(Shared { queue: Pool::queue(limits), threads: Mutex::new(Vec::new()), synchronous: true }),
+        }
+    }
+
+    /// Adds a worker generating meshes of `scalar_field` with `MeshJob`.
+    pub fn worker(&self, scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static) -> Worker {
+        self.worker_with_mesher(scalar_field, MeshJob)
+    }
 
-        let parent = Parent {
-            tasks: receiver_task,
-            results: sender_result,
+    /// Adds a worker generating meshes of `scalar_field` with `mesher`.
+    pub fn worker_with_mesher(&self, scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static, mesher: impl Job<Output = Vec<Vertex>> + Sync) -> Worker {
+        let generator = Generator { scalar_field: Arc::new(scalar_field), material_field: None, mesher: Arc::new(mesher) };
+        Worker::register(self.shared.clone(), Some(generator))
+    }
+
+    /// Adds a worker generating meshes with `MeshJob` of a field returning
+    /// both density and material, blending materials across each mesh.
+    pub fn worker_with_materials(&self, field: impl Fn(f64, f64, f64) -> (f64, Material) + Send + Sync + 'static) -> Worker {
+        let (scalar_field, material_field) = material::split(field);
+        let generator = Generator {
+            scalar_field,
+            material_field: Some(material_field),
+            mesher: Arc::new(MeshJob),
         };
+        Worker::register(self.shared.clone(), Some(generator))
+    }
 
-        thread::spawn(move || {
-            Worker::run(parent, scalar_field);
-        });
+    pub fn threads(&self) -> usize {
+        self.shared.threads.lock().unwrap().len()
+    }
+
+    pub fn status(&self) -> WorkerStatus {
+        self.shared.status()
+    }
+
+    pub fn metrics(&self) -> WorkerMetrics {
+        self.shared.metrics()
+    }
+
+    /// Times every scalar field sample of the mesh tasks from now on, so
+    /// `WorkerMetrics::field` tells field time from meshing time. Off by
+    /// default: the clock reads cost about as much as a cheap field.
+    pub fn set_field_timing(&self, enabled: bool) {
+        self.shared.queue.time_field.store(enabled, Ordering::Relaxed);
+    }
 
-        Worker {
-            tasks: sender_task,
-            results: receiver_result,
+    fn queue(limits: QueueLimits) -> Arc<Queue> {
+        Arc::new(Queue {
+            state: Mutex::new(State {
+                tasks: TaskQueue::new(),
+                in_flight: HashSet::new(),
+                cancelled: HashSet::new(),
+                sources: HashMap::new(),
+                next_source: 0,
+                stats: CancelStats::default(),
+                high_water: 0,
+                coalesced: 0,
+                rejected: 0,
+                panics: 0,
+                metrics: WorkerMetrics::default(),
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+            space: Condvar::new(),
+            limits,
+            started: Instant::now(),
+            time_field: AtomicBool::new(false),
+        })
+    }
+
+    /// Takes the next task off `queue`, marking mesh tasks in flight. Tasks
+    /// whose worker has gone away are dropped.
+    fn take(queue: &Queue, state: &mut State) -> Option<(Task, Arc<Source>)> {
+        while let Some(task) = state.tasks.pop() {
+            queue.space.notify_one();
+            if let Some(source) = state.sources.get(&task.source).cloned() {
+                if !task.is_detached() {
+                    state.in_flight.insert((task.source, task.key, task.epoch));
+                }
+                return Some((task, source));
+            }
         }
+        None
     }
 
-    fn run(parent: Parent, scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + 'static) {
-        let mut tasks = Vec::<Task>::with_capacity(100);
+    fn run(queue: &Queue, mut execute: impl FnMut(Task, &Source) -> Option<Result>) {
         loop {
+            let (task, source) = {
+                let mut state = queue.state.lock().unwrap();
+                loop {
+                    if state.shutdown {
+                        return;
+                    }
+                    match Pool::take(queue, &mut state) {
+                        Some(next) => break next,
+                        None => state = queue.available.wait(state).unwrap(),
+                    }
+                }
+            };
 
-            if tasks.is_empty() {
-                tasks.push(parent.tasks.recv().unwrap());
+            if let Some(result) = execute(task, &source) {
+                source.results_pending.fetch_add(1, Ordering::Relaxed);
+                if source.results.send(result).is_err() {
+                    // The worker is gone, nobody is listening
+                    source.results_pending.fetch_sub(1, Ordering::Relaxed);
+                }
             }
+        }
+    }
 
-            for task in parent.tasks.try_iter() {
-                tasks.push(task);
+    /// Runs `task`. Detached tasks deliver their own output; mesh tasks,
+    /// which the caller has marked in flight, return their result unless
+    /// they were cancelled in the meantime.
+    fn execute(queue: &Queue, source: &Source, mut task: Task) -> Option<Result> {
+        let generator = source.generator.as_ref().expect("Local task without a generator");
+        let context = JobContext::new(task.key, &*generator.scalar_field).with_materials(generator.material_field.as_deref());
+        if let Some(job) = task.job.take() {
+            job.run(&context, &|| queue.state.lock().unwrap().panics += 1);
+            return None;
+        }
+        if let Some(reply) = task.reply.take() {
+            if reply.is_cancelled() {
+                reply.resolve(Err(Error::Cancelled(task.key)));
+            } else {
+                let (data, timing) = Pool::generate(queue, generator, &task);
+                Pool::record(&mut queue.state.lock().unwrap(), &task, &data, timing);
+                reply.resolve(data);
             }
+            return None;
+        }
+
+        let (data, timing) = Pool::generate(queue, generator, &task);
+        Pool::finish(queue, &task, data, timing)
+    }
+
+    /// Meshes `task`'s node, timing the field separately if the pool is set
+    /// to.
+    fn generate(queue: &Queue, generator: &Generator, task: &Task) -> (std::result::Result<Vec<Vertex>, Error>, Timing) {
+        let started = Instant::now();
+        if !queue.time_field.load(Ordering::Relaxed) {
+            let context = JobContext::new(task.key, &*generator.scalar_field).with_materials(generator.material_field.as_deref());
+            let data = job::run_caught(&*generator.mesher, &context);
+            let timing = Timing { queue_wait: started.duration_since(task.queued_at), field: None, total: started.elapsed() };
+            return (data, timing);
+        }
 
-            tasks.sort_by(|a, b| b.level.cmp(&a.level));
+        let field_nanos = AtomicU64::new(0);
+        let timed = |x: f64, y: f64, z: f64| {
+            let start = Instant::now();
+            let value = (generator.scalar_field)(x, y, z);
+            field_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+            value
+        };
+        let timed_materials = |x: f64, y: f64, z: f64| {
+            let start = Instant::now();
+            let value = generator.material_field.as_ref().map_or((0.0, Material::default()), |field| field(x, y, z));
+            field_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+            value
+        };
+        let material_field = generator.material_field.as_ref().map(|_| &timed_materials as &(dyn Fn(f64, f64, f64) -> (f64, Material) + Send + Sync));
+        let context = JobContext::new(task.key, &timed).with_materials(material_field);
+        let data = job::run_caught(&*generator.mesher, &context);
+        let timing = Timing {
+            queue_wait: started.duration_since(task.queued_at),
+            field: Some(Duration::from_nanos(field_nanos.into_inner())),
+            total: started.elapsed(),
+        };
+        (data, timing)
+    }
 
-            let task = tasks.pop().unwrap();
+    /// Records a finished mesh task's metrics, or counts its panic.
+    fn record(state: &mut State, task: &Task, data: &std::result::Result<Vec<Vertex>, Error>, timing: Timing) {
+        match *data {
+            Ok(ref data) => state.metrics.record(task.level, timing, data.len()),
+            Err(Error::Panic(..)) => state.panics += 1,
+            Err(_) => {}
+        }
+    }
 
-            match task.action {
-                TaskAction::Generate => {
-                    let transformed = |x: f64, y: f64, z: f64| scalar_field(
-                        x / f64::from(1 << task.level) + task.x,
-                        y / f64::from(1 << task.level) + task.y,
-                        z / f64::from(1 << task.level) + task.z,
-                    );
+    /// Takes a finished mesh task out of flight, returning its result unless
+    /// it was cancelled in the meantime.
+    fn finish(queue: &Queue, task: &Task, data: std::result::Result<Vec<Vertex>, Error>, timing: Timing) -> Option<Result> {
+        let mut state = queue.state.lock().unwrap();
+        Pool::record(&mut state, task, &data, timing);
+        state.in_flight.remove(&(task.source, task.key, task.epoch));
+        if state.cancelled.remove(&(task.source, task.key, task.epoch)) {
+            state.stats.discarded += 1;
+            return None;
+        }
+        Some(Result {
+            data,
+            key: task.key,
+            epoch: task.epoch,
+            level: task.level,
+            x: task.x,
+            y: task.y,
+            z: task.z,
+        })
+    }
+}
 
-                    let result = Result {
-                        data: Vec::<Vertex>::isosurface(&transformed),
-                        path: task.path.clone(),
-                    };
+impl Shared {
+    fn status(&self) -> WorkerStatus {
+        let threads = self.threads.lock().unwrap();
+        let state = self.queue.state.lock().unwrap();
+        WorkerStatus {
+            threads: threads.len(),
+            alive: threads.iter().filter(|thread| !thread.is_finished()).count(),
+            pending: state.tasks.len(),
+            in_flight: state.in_flight.len(),
+            panics: state.panics,
+        }
+    }
 
-                    parent.results.send(result).unwrap();
-                }
+    fn metrics(&self) -> WorkerMetrics {
+        let state = self.queue.state.lock().unwrap();
+        WorkerMetrics { elapsed: self.queue.started.elapsed(), ..state.metrics.clone() }
+    }
+}
 
-                TaskAction::Cancel => {
-                    let index = tasks.iter().position(|other_task| {
-                        task.x == other_task.x &&
-                        task.y == other_task.y &&
-                        task.z == other_task.z &&
-                        other_task.action == TaskAction::Cancel
-                    });
-
-                    match index {
-                        Some(index) => {
-                            tasks.remove(index);
+impl Drop for Shared {
+    /// Drops pending tasks, lets running ones finish and joins every thread.
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().shutdown = true;
+        self.queue.available.notify_all();
+        for thread in self.threads.get_mut().unwrap().drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Worker {
+    /// Spawns a pool of its own with one mesher thread per available core.
+    pub fn spawn(scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static) -> Worker {
+        Pool::spawn().worker(scalar_field)
+    }
+
+    pub fn with_threads(threads: usize, scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static) -> Worker {
+        Pool::with_threads(threads).worker(scalar_field)
+    }
+
+    pub fn with_limits(threads: usize, limits: QueueLimits, scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static) -> Worker {
+        Pool::with_limits(threads, limits).worker(scalar_field)
+    }
+
+    /// Generates meshes in other processes (see the `universe-worker` binary),
+    /// with one connection and thread per socket. Tasks stay queued and
+    /// prioritized here; jobs from `submit` fail, as they can't be sent over,
+    /// while mesh requests from `request` are generated remotely too. Meshes
+    /// blend the materials of the field the other process serves, if any.
+    ///
+    /// A connection that fails reports an error for the task it was running
+    /// and reconnects for the next one.
+    #[cfg(unix)]
+    pub fn remote<P: AsRef<Path>>(sockets: &[P]) -> std::result::Result<Worker, Error> {
+        Worker::remote_with_limits(sockets, QueueLimits::default())
+    }
+
+    #[cfg(unix)]
+    pub fn remote_with_limits<P: AsRef<Path>>(sockets: &[P], limits: QueueLimits) -> std::result::Result<Worker, Error> {
+        let queue = Pool::queue(limits);
+
+        // Connect everywhere first, so a failure leaves no thread behind
+        let clients = sockets.iter()
+            .map(|socket| Client::connect(socket.as_ref()))
+            .collect::<std::result::Result<Vec<_>, Error>>()?;
+
+        // Owning the threads as they're spawned, the pool joins them if a
+        // later one fails to spawn
+        let shared = Arc::new(Shared { queue: queue.clone(), threads: Mutex::new(Vec::new()), synchronous: false });
+        for (index, mut client) in clients.into_iter().enumerate() {
+            let queue = queue.clone();
+            let thread = thread::Builder::new()
+                .name(format!("mesher-remote-{}", index))
+                .spawn(move || Pool::run(&queue, |mut task, _source| {
+                    if let Some(job) = task.job.take() {
+                        job.fail(Error::Remote(String::from("jobs can't run out of process")));
+                        return None;
+                    }
+                    let started = Instant::now();
+                    let queue_wait = started.duration_since(task.queued_at);
+                    if let Some(reply) = task.reply.take() {
+                        if reply.is_cancelled() {
+                            reply.resolve(Err(Error::Cancelled(task.key)));
+                        } else {
+                            let data = client.generate(task.key);
+                            let timing = Timing { queue_wait, field: None, total: started.elapsed() };
+                            Pool::record(&mut queue.state.lock().unwrap(), &task, &data, timing);
+                            reply.resolve(data);
                         }
-                        None => {}
+                        return None;
                     }
+                    let data = client.generate(task.key);
+                    let timing = Timing { queue_wait, field: None, total: started.elapsed() };
+                    Pool::finish(&queue, &task, data, timing)
+                }))?;
+            shared.threads.lock().unwrap().push(thread);
+        }
+
+        Ok(Worker::register(shared, None))
+    }
+
+    /// A worker with a synchronous pool of its own (see `Pool::synchronous`).
+    pub fn synchronous(scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static) -> Worker {
+        Pool::synchronous().worker(scalar_field)
+    }
+
+    pub fn synchronous_with_limits(limits: QueueLimits, scalar_field: impl Fn(f64, f64, f64) -> f64 + Send + Sync + 'static) -> Worker {
+        Pool::synchronous_with_limits(limits).worker(scalar_field)
+    }
+
+    fn register(shared: Arc<Shared>, generator: Option<Generator>) -> Worker {
+        let (sender_result, receiver_result) = sync_channel::<Result>(shared.queue.limits.results);
+        let source = {
+            let mut state = shared.queue.state.lock().unwrap();
+            let source = Arc::new(Source {
+                id: state.next_source,
+                generator,
+                results: sender_result,
+                results_pending: AtomicUsize::new(0),
+            });
+            state.next_source += 1;
+            state.sources.insert(source.id, source.clone());
+            source
+        };
+        Worker { shared, source, results: receiver_result }
+    }
+
+    /// Threads in the pool, shared with any other workers using it.
+    pub fn threads(&self) -> usize {
+        self.shared.threads.lock().unwrap().len()
+    }
+
+    pub fn is_synchronous(&self) -> bool {
+        self.shared.synchronous
+    }
+
+    /// The field this worker meshes, or `None` for remote workers.
+    pub fn scalar_field(&self) -> Option<Arc<ScalarField>> {
+        self.source.generator.as_ref().map(|generator| generator.scalar_field.clone())
+    }
+
+    /// Runs queued tasks on the calling thread until the queue is empty,
+    /// except for the mesh tasks of workers whose result channel is full,
+    /// which stay queued. In a shared pool this runs every worker's tasks.
+    /// Returns the number of tasks run. Does nothing for threaded workers.
+    pub fn pump(&self) -> usize {
+        if !self.is_synchronous() {
+            return 0;
+        }
+
+        let queue = &*self.shared.queue;
+        let mut count = 0;
+        loop {
+            let (task, source) = {
+                let mut state = queue.state.lock().unwrap();
+                // A full result channel holds up only its own worker's
+                // meshes; they go back before the lock is released
+                let mut held = Vec::new();
+                let next = loop {
+                    match Pool::take(queue, &mut state) {
+                        Some((task, source)) if !task.is_detached() && source.results_pending.load(Ordering::Relaxed) >= queue.limits.results => {
+                            state.in_flight.remove(&(task.source, task.key, task.epoch));
+                            held.push(task);
+                        }
+                        next => break next,
+                    }
+                };
+                for task in held {
+                    state.tasks.push(task);
+                }
+                match next {
+                    Some(next) => next,
+                    None => break,
+                }
+            };
+
+            count += 1;
+            if let Some(result) = Pool::execute(queue, &source, task) {
+                source.results_pending.fetch_add(1, Ordering::Relaxed);
+                if source.results.try_send(result).is_err() {
+                    source.results_pending.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+        }
+        count
+    }
+
+    /// Queues a `Generate` task, waiting for space if the queue is full (or,
+    /// for synchronous workers, running tasks to make space), or
+    /// cancels the `Generate` task with the same key and epoch, whether it is
+    /// still queued or already running.
+    ///
+    /// Don't call this from the thread receiving results while the result
+    /// channel can fill up: use `try_send` there instead. A synchronous
+    /// worker that can't make space, because the result channel is full,
+    /// hands the task back. Cancellations are never refused.
+    #[allow(clippy::result_large_err)]
+    pub fn send(&self, task: Task) -> std::result::Result<(), Task> {
+        let queue = &*self.shared.queue;
+        let mut state = queue.state.lock().unwrap();
+        if task.action == TaskAction::Generate {
+            while self.is_full(&state, task.key) {
+                state = match self.wait_for_space(state) {
+                    Some(state) => state,
+                    None => return Err(task),
+                };
+            }
+        }
+        self.accept(&mut state, task);
+        Ok(())
+    }
+
+    /// Waits until a task leaves the queue or, for synchronous workers, runs
+    /// tasks to make space. Returns `None` if a synchronous worker can't run
+    /// any.
+    fn wait_for_space<'a>(&'a self, state: MutexGuard<'a, State>) -> Option<MutexGuard<'a, State>> {
+        let queue = &*self.shared.queue;
+        if self.is_synchronous() {
+            drop(state);
+            if self.pump() == 0 {
+                return None;
+            }
+            Some(queue.state.lock().unwrap())
+        } else {
+            Some(queue.space.wait(state).unwrap())
+        }
+    }
+
+    /// Like `send`, but hands the task back instead of waiting when the queue
+    /// is full. Cancellations are never refused.
+    #[allow(clippy::result_large_err)]
+    pub fn try_send(&self, task: Task) -> std::result::Result<(), Task> {
+        let mut state = self.shared.queue.state.lock().unwrap();
+        if task.action == TaskAction::Generate && self.is_full(&state, task.key) {
+            state.rejected += 1;
+            return Err(task);
+        }
+        self.accept(&mut state, task);
+        Ok(())
+    }
+
+    /// A task replacing one for the same key takes no extra space.
+    fn is_full(&self, state: &State, key: NodeKey) -> bool {
+        state.tasks.len() >= self.shared.queue.limits.tasks && !state.tasks.contains(self.source.id, key)
+    }
+
+    fn accept(&self, state: &mut State, mut task: Task) {
+        let queue = &*self.shared.queue;
+        task.source = self.source.id;
+        match task.action {
+            TaskAction::Generate => {
+                task.queued_at = Instant::now();
+                if state.tasks.push(task) {
+                    state.coalesced += 1;
+                }
+                state.high_water = state.high_water.max(state.tasks.len());
+                queue.available.notify_one();
+            }
+
+            TaskAction::Cancel => {
+                state.stats.requested += 1;
+                if state.tasks.remove(task.source, task.key, task.epoch) {
+                    state.stats.dequeued += 1;
+                    queue.space.notify_one();
+                } else if state.in_flight.contains(&(task.source, task.key, task.epoch)) {
+                    state.cancelled.insert((task.source, task.key, task.epoch));
+                }
+            }
+        }
+    }
+
+    /// Queues `job` to run for the node `key`, prioritized alongside mesh
+    /// generation by the same metric. Waits for space like `send`; if a
+    /// synchronous worker can't make any, the handle resolves to
+    /// `Error::QueueFull`.
+    pub fn submit<J: Job>(&self, key: NodeKey, job: J) -> JobHandle<J::Output> {
+        let (erased, handle) = job::erase(job);
+        if let Err(mut task) = self.send_detached(Task { job: Some(erased), ..Task::generate(key, 0) }) {
+            if let Some(job) = task.job.take() {
+                job.fail(Error::QueueFull);
+            }
+        }
+        handle
+    }
+
+    /// Generates the mesh for `key`, returning a handle that resolves to it
+    /// (or to the error or cancellation that prevented it). Unlike `send`,
+    /// the mesh doesn't go through `try_iter`, and requests for the same key
+    /// don't coalesce. Waits for space like `submit`.
+    pub fn request(&self, key: NodeKey) -> ChunkHandle {
+        let (reply, handle) = job::promise();
+        if let Err(mut task) = self.send_detached(Task { reply: Some(reply), ..Task::generate(key, 0) }) {
+            if let Some(reply) = task.reply.take() {
+                reply.resolve(Err(Error::QueueFull));
+            }
+        }
+        handle
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn send_detached(&self, task: Task) -> std::result::Result<(), Task> {
+        let queue = &*self.shared.queue;
+        let mut state = queue.state.lock().unwrap();
+        while state.tasks.len() >= queue.limits.tasks {
+            state = match self.wait_for_space(state) {
+                Some(state) => state,
+                None => return Err(task),
+            };
+        }
+        self.accept(&mut state, task);
+        Ok(())
+    }
+
+    /// Whether `try_send` would currently accept a new task.
+    pub fn has_capacity(&self) -> bool {
+        self.shared.queue.state.lock().unwrap().tasks.len() < self.shared.queue.limits.tasks
+    }
+
+    /// Depth of the pool's task queue, and of this worker's result channel.
+    pub fn queue_metrics(&self) -> QueueMetrics {
+        let queue = &*self.shared.queue;
+        let state = queue.state.lock().unwrap();
+        QueueMetrics {
+            pending: state.tasks.len(),
+            capacity: queue.limits.tasks,
+            high_water: state.high_water,
+            in_flight: state.in_flight.len(),
+            results_pending: self.source.results_pending.load(Ordering::Relaxed),
+            results_capacity: queue.limits.results,
+            coalesced: state.coalesced,
+            rejected: state.rejected,
+        }
+    }
+
+    /// The pool's health, across every worker sharing it.
+    pub fn status(&self) -> WorkerStatus {
+        self.shared.status()
+    }
+
+    /// Timing, vertex count and per-level throughput statistics of the mesh
+    /// tasks the pool finished so far (failed ones aren't counted).
+    pub fn metrics(&self) -> WorkerMetrics {
+        self.shared.metrics()
+    }
+
+    pub fn cancel_stats(&self) -> CancelStats {
+        self.shared.queue.state.lock().unwrap().stats
+    }
+
+    /// Sets the metric used to order this worker's pending tasks and
+    /// re-prioritizes the ones already queued, e.g. after the camera moved.
+    pub fn set_priority(&self, metric: Metric) {
+        self.shared.queue.state.lock().unwrap().tasks.set_source_metric(self.source.id, metric);
+    }
+
+    /// See `Pool::set_field_timing`; affects every worker in the pool.
+    pub fn set_field_timing(&self, enabled: bool) {
+        self.shared.queue.time_field.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Recomputes pending priorities of every worker in the pool with their
+    /// current metrics.
+    pub fn reprioritize(&self) {
+        self.shared.queue.state.lock().unwrap().tasks.reprioritize();
+    }
+
+    pub fn try_iter(&self) -> impl Iterator<Item = Result> + '_ {
+        self.results.try_iter().inspect(move |_| {
+            self.source.results_pending.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    /// Blocks until the next result is available.
+    pub fn recv(&self) -> Option<Result> {
+        let result = self.results.recv().ok();
+        if result.is_some() {
+            self.source.results_pending.fetch_sub(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl Drop for Worker {
+    /// Drops this worker's pending tasks and leaves the pool, which stops
+    /// its threads if nothing else uses it.
+    fn drop(&mut self) {
+        {
+            let mut state = self.shared.queue.state.lock().unwrap();
+            let id = self.source.id;
+            state.sources.remove(&id);
+            state.tasks.remove_source(id);
+        }
+        self.shared.queue.space.notify_all();
+        // Threads blocked on a full result channel give up once it's closed
+        let (_, closed) = sync_channel(0);
+        drop(std::mem::replace(&mut self.results, closed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate(worker: &Worker, key: NodeKey) {
+        assert!(worker.send(Task::generate(key, 0)).is_ok());
+    }
+
+    #[test]
+    fn panic_reported() {
+        let worker = Worker::with_threads(2, |x: f64, y: f64, z: f64| {
+            if x > 0.4 {
+                panic!("bad field");
+            }
+            x.powi(2) + y.powi(2) + z.powi(2) - 0.2
+        });
+
+        generate(&worker, NodeKey::root().child(0));
+        let result = worker.recv().expect("Worker stopped");
+        match result.data {
+            Err(Error::Panic(key, message)) => {
+                assert!(key == NodeKey::root().child(0));
+                assert!(message == "bad field");
+            }
+            _ => panic!("Expected a panic result"),
+        }
+
+        // The thread survives and keeps meshing
+        generate(&worker, NodeKey::root().child(7));
+        assert!(worker.recv().expect("Worker stopped").data.is_ok());
+
+        let status = worker.status();
+        assert!(status.panics == 1);
+        assert!(status.alive == 2);
+
+        // Requested meshes and submitted jobs count their panics too
+        assert!(matches!(worker.request(NodeKey::root()).wait(), Err(Error::Panic(..))));
+        let job = worker.submit(NodeKey::root(), crate::job::FieldStatsJob { resolution: 16 });
+        assert!(matches!(job.wait(), Err(Error::Panic(..))));
+        assert!(worker.status().panics == 3);
+    }
+
+    #[test]
+    fn synchronous_full() {
+        let limits = QueueLimits { tasks: 1, results: 1 };
+        let worker = Worker::synchronous_with_limits(limits, |x, y, z| x * x + y * y + z * z - 0.2);
+        generate(&worker, NodeKey::root().child(0));
+        // Runs the first task to make space, filling the result channel
+        generate(&worker, NodeKey::root().child(1));
+
+        let refused = worker.send(Task::generate(NodeKey::root().child(2), 0)).unwrap_err();
+        assert!(refused.key == NodeKey::root().child(2));
+        match worker.request(NodeKey::root()).wait() {
+            Err(Error::QueueFull) => {}
+            _ => panic!("Expected the request to be refused"),
+        }
+
+        assert!(worker.try_iter().count() == 1);
+        assert!(worker.send(refused).is_ok());
+    }
+
+    #[test]
+    fn jobs() {
+        use crate::job::FieldStatsJob;
+
+        let worker = Worker::synchronous(|x: f64, y: f64, z: f64| x.powi(2) + y.powi(2) + z.powi(2) - 0.2);
+        let inner = worker.submit(NodeKey::root().child(0).child(7), FieldStatsJob { resolution: 4 });
+        let outer = worker.submit(NodeKey::root().child(0).child(0), FieldStatsJob { resolution: 4 });
+        let skipped = worker.submit(NodeKey::root(), FieldStatsJob { resolution: 4 });
+        skipped.cancel();
+        generate(&worker, NodeKey::root());
+        assert!(inner.try_get().is_none());
+
+        assert!(worker.pump() == 4);
+        assert!(inner.wait().unwrap().inside == 1.0);
+        assert!(outer.wait().unwrap().inside == 0.0);
+        match skipped.wait() {
+            Err(Error::Cancelled(key)) => assert!(key == NodeKey::root()),
+            _ => panic!("Expected the job to be cancelled"),
+        }
+
+        // Output taken with `try_get` isn't waited for again
+        let polled = worker.submit(NodeKey::root(), FieldStatsJob { resolution: 4 });
+        worker.pump();
+        assert!(matches!(polled.try_get(), Some(Ok(_))));
+        assert!(matches!(polled.try_get(), Some(Err(Error::OutputTaken))));
+        assert!(matches!(polled.wait(), Err(Error::OutputTaken)));
+        assert!(worker.try_iter().count() == 1);
+
+        // Only mesh tasks are timed, and the field only when asked to
+        let metrics = worker.metrics();
+        assert!(metrics.vertices.count() == 1);
+        assert!(metrics.levels.len() == 1 && metrics.levels[0].chunks == 1);
+        assert!(metrics.field.count() == 0 && metrics.meshing.count() == 1);
+        worker.set_field_timing(true);
+        generate(&worker, NodeKey::root());
+        worker.pump();
+        assert!(worker.metrics().field.count() == 1);
+    }
+
+    #[test]
+    fn request() {
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Wake, Waker};
+        use std::future::Future;
+        use std::pin::Pin;
+
+        struct Unpark(thread::Thread);
+
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        fn block_on<F: Future + Unpin>(mut future: F) -> F::Output {
+            let waker = Waker::from(Arc::new(Unpark(thread::current())));
+            let mut context = Context::from_waker(&waker);
+            loop {
+                match Pin::new(&mut future).poll(&mut context) {
+                    Poll::Ready(output) => return output,
+                    Poll::Pending => thread::park(),
                 }
             }
+        }
+
+        let worker = Worker::with_threads(2, |x: f64, y: f64, z: f64| x.powi(2) + y.powi(2) + z.powi(2) - 0.2);
+        let mesh = block_on(worker.request(NodeKey::root())).unwrap();
+        assert!(!mesh.is_empty());
+        // Requests don't show up among polled results
+        assert!(worker.try_iter().count() == 0);
+
+        let synchronous = Worker::synchronous(|x: f64, y: f64, z: f64| x.powi(2) + y.powi(2) + z.powi(2) - 0.2);
+        let skipped = synchronous.request(NodeKey::root());
+        skipped.cancel();
+        assert!(synchronous.pump() == 1);
+        assert!(matches!(skipped.wait(), Err(Error::Cancelled(_))));
+
+        // Dropping the worker resolves requests it never ran
+        let pending = synchronous.request(NodeKey::root().child(0));
+        drop(synchronous);
+        assert!(matches!(pending.wait(), Err(Error::WorkerStopped)));
+    }
 
+    #[test]
+    fn materials() {
+        let worker = Pool::with_threads(1).worker_with_materials(|x: f64, y: f64, z: f64| {
+            (x.powi(2) + y.powi(2) + z.powi(2) - 0.2, if x < 0.0 { Material::Sand } else { Material::Snow })
+        });
+        let data = worker.request(NodeKey::root()).wait().unwrap();
+        assert!(data.len() > 0);
+        for vertex in &data {
+            assert!((vertex.material.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+            if vertex.position[0] < -0.1 {
+                assert!(vertex.material == Material::Sand.weights());
+            }
         }
+        // Vertices on the boundary blend both
+        assert!(data.iter().any(|vertex| vertex.material[Material::Sand.index()] > 0.0 && vertex.material[Material::Snow.index()] > 0.0));
     }
 
-    pub fn send(&self, task: Task) {
-        self.tasks.send(task).unwrap()
+    #[test]
+    fn shared_pool() {
+        let pool = Pool::synchronous();
+        let small = pool.worker(|x: f64, y: f64, z: f64| x.powi(2) + y.powi(2) + z.powi(2) - 0.05);
+        let large = pool.worker(|x: f64, y: f64, z: f64| x.powi(2) + y.powi(2) + z.powi(2) - 0.2);
+        // The small body is further away: its root waits for the large
+        // body's children
+        small.set_priority(Arc::new(|level, _x, _y, _z| f64::from(level) + 1.5));
+        generate(&small, NodeKey::root());
+        generate(&large, NodeKey::root());
+        generate(&large, NodeKey::root().child(0));
+
+        // Same key in both workers: neither coalesces nor cancels the other
+        assert!(small.send(Task::cancel(NodeKey::root().child(0), 0)).is_ok());
+        assert!(pool.status().pending == 3);
+
+        assert!(small.pump() == 3);
+        let large_results: Vec<_> = large.try_iter().collect();
+        let small_results: Vec<_> = small.try_iter().collect();
+        assert!(large_results.len() == 2 && small_results.len() == 1);
+        assert!(large_results[0].key == NodeKey::root());
+        assert!(small_results[0].data.as_ref().unwrap().len() < large_results[0].data.as_ref().unwrap().len());
+        assert!(pool.metrics().chunks() == 3);
+
+        // Dropping a worker drops only its own tasks
+        generate(&small, NodeKey::root());
+        generate(&large, NodeKey::root());
+        drop(small);
+        assert!(pool.status().pending == 1);
+        assert!(large.pump() == 1);
+
+        // A worker nobody receives from holds up only its own tasks
+        let pool = Pool::synchronous_with_limits(QueueLimits { tasks: 16, results: 1 });
+        let sphere = |x: f64, y: f64, z: f64| x.powi(2) + y.powi(2) + z.powi(2) - 0.2;
+        let (stuck, busy) = (pool.worker(sphere), pool.worker(sphere));
+        generate(&stuck, NodeKey::root());
+        assert!(stuck.pump() == 1);
+        generate(&stuck, NodeKey::root().child(0));
+        generate(&busy, NodeKey::root());
+        generate(&busy, NodeKey::root().child(0));
+        assert!(busy.pump() == 1 && busy.try_iter().count() == 1);
+        assert!(busy.pump() == 1 && busy.try_iter().count() == 1);
+        assert!(pool.status().pending == 1);
+        assert!(stuck.try_iter().count() == 1);
+        assert!(stuck.pump() == 1 && stuck.try_iter().count() == 1);
     }
 
-    pub fn try_iter(&self) -> TryIter<Result> {
-        self.results.try_iter()
+    #[test]
+    fn drop_joins_threads() {
+        let worker = Worker::with_threads(2, |x: f64, y: f64, z: f64| x.powi(2) + y.powi(2) + z.powi(2) - 0.2);
+        for index in 0..8 {
+            generate(&worker, NodeKey::root().child(index));
+        }
+        drop(worker);
     }
 }